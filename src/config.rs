@@ -1,14 +1,36 @@
+use crate::notify::NotificationEvent;
 use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use cron::Schedule;
 use humantime;
 use serde::Deserialize;
 use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum ConfigError {
     InvalidCronExpression(String),
     InvalidDuration(String),
     InvalidToml(String),
+    InvalidGroup(String),
+    InvalidHook(String),
+    InvalidUpload(String),
+    InvalidRetry(String),
+    InvalidPolicy(String),
+    InvalidTimezone(String),
+    InvalidSchedule(String),
+    UnsupportedVersion(u32),
+    InvalidCredentials(String),
+    InvalidInterpolation(String),
+    InvalidInclude(String),
+    /// Invalid value of a key, with its line and column in the file when known. The file is
+    /// named when the configuration includes other files.
+    InvalidKey {
+        key: String,
+        file: Option<String>,
+        location: Option<(usize, usize)>,
+        error: Box<ConfigError>,
+    },
 }
 
 impl std::error::Error for ConfigError {}
@@ -30,37 +52,300 @@ Supported specification: https://docs.oracle.com/cd/E12058_01/doc/doc.1014/e1203
             ConfigError::InvalidToml(e) => {
                 write!(f, "Invalid TOML configuration: {}", e)
             }
+            ConfigError::InvalidGroup(e) => {
+                write!(f, "Invalid consistency group: {}", e)
+            }
+            ConfigError::InvalidHook(e) => {
+                write!(f, "Invalid hook: {}", e)
+            }
+            ConfigError::InvalidUpload(e) => {
+                write!(f, "Invalid upload settings: {}", e)
+            }
+            ConfigError::InvalidRetry(e) => {
+                write!(f, "Invalid retry policy: {}", e)
+            }
+            ConfigError::InvalidPolicy(e) => {
+                write!(f, "Invalid backup policy: {}", e)
+            }
+            ConfigError::InvalidTimezone(e) => {
+                write!(f, "Invalid time zone: {}", e)
+            }
+            ConfigError::InvalidSchedule(e) => {
+                write!(f, "Invalid schedule settings: {}", e)
+            }
+            ConfigError::InvalidCredentials(e) => {
+                write!(f, "Invalid S3 credentials: {}", e)
+            }
+            ConfigError::InvalidInterpolation(e) => {
+                write!(f, "Invalid variable interpolation: {}", e)
+            }
+            ConfigError::InvalidInclude(e) => {
+                write!(f, "Invalid include: {}", e)
+            }
+            ConfigError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Unsupported configuration version {version}, supported versions are 1 to {CONFIG_VERSION}"
+                )
+            }
+            ConfigError::InvalidKey {
+                key,
+                file,
+                location,
+                error,
+            } => {
+                write!(f, "`{key}`")?;
+                if let Some(file) = file {
+                    write!(f, " in {file}")?;
+                }
+                if let Some((line, column)) = location {
+                    write!(f, " at line {line}, column {column}")?;
+                }
+                write!(f, ": {error}")
+            }
         }
     }
 }
 
+/// Version of the configuration schema. It is increased when the configuration changes in a way
+/// that requires a migration, so that older files are detected.
+pub const CONFIG_VERSION: u32 = 1;
+
 #[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Version of the schema of the file, the current version when missing
+    version: Option<u32>,
+    /// Files merged into the configuration, as globs relative to the directory of the file
     #[serde(default)]
+    include: Vec<String>,
     pub backup: BackupPolicy,
-    #[serde(default)]
     pub cleanup: CleanupPolicy,
     pub s3: S3,
+    #[serde(default)]
+    pub upload: UploadPolicy,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub state: StatePolicy,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub notify: NotifyPolicy,
+    #[serde(default)]
+    pub shutdown: ShutdownPolicy,
+    #[serde(default)]
+    pub schedule: SchedulePolicy,
 }
 
 impl Config {
+    /// Parse a configuration, with its environment variables interpolated
     pub fn try_from(toml: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let config: Config = toml::from_str(toml)?;
-        config.validate()?;
+        let toml = interpolate(toml, |name| std::env::var(name).ok())?;
+        Self::parse(&toml)
+    }
+
+    /// Load the configuration file at `path`, with its environment variables interpolated and the
+    /// files matching its `include` globs merged in order: tables are merged, arrays extended and
+    /// other values replaced.
+    pub async fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let toml = interpolate(&read_config(path).await?, |name| std::env::var(name).ok())?;
+        let mut table: toml::Table = toml::from_str(&toml).map_err(|e| toml_error(&toml, e))?;
+        let patterns: Vec<String> = match table.get("include") {
+            Some(include) => include
+                .clone()
+                .try_into()
+                .map_err(|_| ConfigError::InvalidInclude("expected a list of globs".to_string()))?,
+            None => return Self::parse(&toml),
+        };
+
+        let base = path.parent().unwrap_or(Path::new("."));
+        let mut sources = vec![(path.to_path_buf(), toml)];
+        for file in included_files(base, &patterns).await? {
+            let toml = interpolate(&read_config(&file).await?, |name| std::env::var(name).ok())
+                .map_err(|e| ConfigError::InvalidInclude(format!("{}: {e}", file.display())))?;
+            let fragment = toml::from_str::<toml::Table>(&toml)
+                .map_err(|e| toml_error(&toml, e))
+                .map_err(|e| ConfigError::InvalidInclude(format!("{}: {e}", file.display())))?;
+            if fragment.contains_key("include") {
+                return Err(ConfigError::InvalidInclude(format!(
+                    "{}: included files cannot include other files",
+                    file.display()
+                ))
+                .into());
+            }
+            merge(&mut table, fragment);
+            sources.push((file, toml));
+        }
+
+        // Errors are located in the last file defining the key, which is the one it comes from
+        let locate_in_sources = |key: &str| {
+            sources.iter().rev().find_map(|(file, toml)| {
+                locate(toml, key).map(|location| (file.display().to_string(), location))
+            })
+        };
+        let config: Config = table.try_into().map_err(|e: toml::de::Error| {
+            match error_key(&e).and_then(|key| locate_in_sources(&key)) {
+                Some((file, (line, column))) => ConfigError::InvalidToml(format!(
+                    "in {file} at line {line}, column {column}\n{e}"
+                )),
+                None => {
+                    ConfigError::InvalidToml(format!("in {} or its includes\n{e}", path.display()))
+                }
+            }
+        })?;
+        config.validate().map_err(|e| match e {
+            ConfigError::InvalidKey { key, error, .. } => {
+                let (file, location) = locate_in_sources(&key).unzip();
+                ConfigError::InvalidKey {
+                    key,
+                    file,
+                    location,
+                    error,
+                }
+            }
+            e => e,
+        })?;
+        Ok(config)
+    }
+
+    fn parse(toml: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let config: Config = toml::from_str(toml).map_err(|e| toml_error(toml, e))?;
+        config.validate().map_err(|e| match e {
+            ConfigError::InvalidKey { key, error, .. } => ConfigError::InvalidKey {
+                location: locate(toml, &key),
+                file: None,
+                key,
+                error,
+            },
+            e => e,
+        })?;
         Ok(config)
     }
-    /// Validate the configuration. Returns Ok(()) if valid, or ConfigError if invalid.
+
+    /// Globs of the files merged into the configuration
+    pub fn includes(&self) -> &[String] {
+        &self.include
+    }
+
+    /// Version of the schema of the file
+    pub fn version(&self) -> u32 {
+        self.version.unwrap_or(CONFIG_VERSION)
+    }
+
+    /// Validate the configuration. Returns Ok(()) if valid, or ConfigError naming the invalid
+    /// key if invalid.
     fn validate(&self) -> Result<(), ConfigError> {
+        if !(1..=CONFIG_VERSION).contains(&self.version()) {
+            return at(
+                "version",
+                Err(ConfigError::UnsupportedVersion(self.version())),
+            );
+        }
         // Validate expressions
-        self.backup.schedule()?;
-        self.backup.incremental()?;
-        self.cleanup.schedule()?;
-        self.cleanup.keep_duration()?;
+        at("backup.schedule", self.backup.schedule())?;
+        at("backup.incremental", self.backup.incremental())?;
+        at("cleanup.schedule", self.cleanup.schedule())?;
+        at("cleanup.keep_duration", self.cleanup.keep_duration())?;
+        at("backup.group", self.backup.validate_groups())?;
+        at("backup.hook", self.backup.validate_hooks())?;
+        at(
+            "backup.send_flags",
+            validate_send_flags(&self.backup.send_flags),
+        )?;
+        at("backup.policy", self.backup.validate_policies())?;
+        at("upload", self.upload.validate())?;
+        at("retry", self.retry.validate())?;
+        at("state.listing_max_age", self.state.listing_max_age())?;
+        at("notify.rate_limit", self.notify.rate_limit())?;
+        at("notify.summary", self.notify.summary())?;
+        at("shutdown.grace_period", self.shutdown.grace_period())?;
+        at("schedule.catch_up_window", self.schedule.catch_up_window())?;
+        // The last runs are only known across restarts when the state is persisted
+        if self.schedule.catch_up_window.is_some() && self.state.dir.is_none() {
+            return at(
+                "schedule.catch_up_window",
+                Err(ConfigError::InvalidSchedule(
+                    "catching up on missed runs requires `state.dir`".to_string(),
+                )),
+            );
+        }
+        at("schedule.jitter", self.schedule.jitter())?;
+        at("schedule.timezone", self.schedule.timezone())?;
+        at("s3.key_id_file", self.s3.key_files())?;
         Ok(())
     }
+
+    /// Every schedule of the configuration, with the key defining it
+    pub fn schedules(&self) -> Result<Vec<(String, Schedule)>, ConfigError> {
+        let mut schedules = vec![
+            ("backup.schedule".to_string(), self.backup.schedule()?),
+            ("backup.incremental".to_string(), self.backup.incremental()?),
+            ("cleanup.schedule".to_string(), self.cleanup.schedule()?),
+        ];
+        for (i, policy) in self.backup.policies.iter().enumerate() {
+            if let Some(schedule) = &policy.schedule {
+                schedules.push((format!("backup.policy[{i}].schedule"), to_cron(schedule)?));
+            }
+            if let Some(incremental) = &policy.incremental {
+                schedules.push((
+                    format!("backup.policy[{i}].incremental"),
+                    to_cron(incremental)?,
+                ));
+            }
+        }
+        if let Some(summary) = self.notify.summary()? {
+            schedules.push(("notify.summary".to_string(), summary));
+        }
+        if let Some(window) = self.upload.window()? {
+            schedules.push(("upload.window".to_string(), window));
+        }
+        for (i, rule) in self.upload.bandwidth.iter().enumerate() {
+            if let Some(schedule) = rule.schedule()? {
+                schedules.push((format!("upload.bandwidth[{i}].schedule"), schedule));
+            }
+        }
+        Ok(schedules)
+    }
+
+    /// Backup policies: the default one, from `[backup]` and `[cleanup]`, then the ones of
+    /// `[[backup.policy]]`
+    pub fn policies(&self) -> Vec<EffectivePolicy<'_>> {
+        let default = EffectivePolicy {
+            name: DEFAULT_POLICY,
+            schedule: &self.backup.schedule,
+            incremental: &self.backup.incremental,
+            send_flags: &self.backup.send_flags,
+            keep_min: self.cleanup.keep_min,
+            keep_duration: &self.cleanup.keep_duration,
+        };
+        let policies = self.backup.policies.iter().map(|policy| EffectivePolicy {
+            name: &policy.name,
+            schedule: policy.schedule.as_deref().unwrap_or(default.schedule),
+            incremental: policy.incremental.as_deref().unwrap_or(default.incremental),
+            send_flags: policy.send_flags.as_deref().unwrap_or(default.send_flags),
+            keep_min: policy.keep_min.unwrap_or(default.keep_min),
+            keep_duration: policy
+                .keep_duration
+                .as_deref()
+                .unwrap_or(default.keep_duration),
+        });
+        std::iter::once(default).chain(policies).collect()
+    }
+
+    /// Policy named `name`, the default policy if there is no such policy
+    pub fn policy(&self, name: &str) -> EffectivePolicy<'_> {
+        let policies = self.policies();
+        policies
+            .iter()
+            .find(|policy| policy.name == name)
+            .copied()
+            .unwrap_or(policies[0])
+    }
 }
 
 #[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct BackupPolicy {
     /// When to take snapshots (cron expression).
     /// Create an incremental backup unless the threshold for full backup is met.
@@ -71,11 +356,31 @@ pub struct BackupPolicy {
     /// When to take incremental snapshots (cron expression).
     ///       sec  min   hour   day of month   month   day of week   year
     /// E.g., "*    *      * 15 * * *" for monthly on the 15th at midnight UTC.
-    #[serde(default)]
     incremental: String,
     /// List of glob pattern to specify volumes
     #[serde(default)]
     pub volumes: Vec<String>,
+    /// List of glob pattern to specify volumes which are not backed up, even if they match
+    /// other patterns or opt in with the `zfs2s3:backup` property
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Delete the objects of the volumes excluded by `exclude` or the `zfs2s3:backup` property
+    /// from S3, and their local snapshots taken by zfs2s3. By default they are kept, so that
+    /// opting out does not delete the backups.
+    #[serde(default)]
+    pub delete_excluded: bool,
+    /// Flags of `zfs send`, e.g. ["--raw"]
+    #[serde(default)]
+    send_flags: Vec<String>,
+    /// Settings of the volumes matching other patterns
+    #[serde(default, rename = "policy")]
+    pub policies: Vec<VolumePolicy>,
+    /// Groups of volumes that must be snapshotted at the same instant
+    #[serde(default, rename = "group")]
+    pub groups: Vec<ConsistencyGroup>,
+    /// Commands to run around snapshots and uploads
+    #[serde(default, rename = "hook")]
+    pub hooks: Vec<Hook>,
 }
 
 impl BackupPolicy {
@@ -84,55 +389,977 @@ impl BackupPolicy {
         to_cron(expression)
     }
 
-    pub fn incremental(&self) -> Result<Schedule, ConfigError> {
-        let expression = self.incremental.as_str();
-        to_cron(expression)
+    pub fn incremental(&self) -> Result<Schedule, ConfigError> {
+        let expression = self.incremental.as_str();
+        to_cron(expression)
+    }
+
+    fn validate_groups(&self) -> Result<(), ConfigError> {
+        let mut names = std::collections::HashSet::new();
+        for group in self.groups.iter() {
+            if group.name.is_empty() {
+                return Err(ConfigError::InvalidGroup("group name is empty".to_string()));
+            }
+            if group.volumes.is_empty() {
+                return Err(ConfigError::InvalidGroup(format!(
+                    "group {} has no volumes",
+                    group.name
+                )));
+            }
+            if !names.insert(group.name.as_str()) {
+                return Err(ConfigError::InvalidGroup(format!(
+                    "group {} is defined more than once",
+                    group.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_policies(&self) -> Result<(), ConfigError> {
+        let mut names = std::collections::HashSet::new();
+        for policy in self.policies.iter() {
+            let invalid = |e: String| ConfigError::InvalidPolicy(format!("{}: {e}", policy.name));
+            if policy.name.is_empty() || policy.name == DEFAULT_POLICY {
+                return Err(ConfigError::InvalidPolicy(format!(
+                    "policy name `{}` is reserved",
+                    policy.name
+                )));
+            }
+            if !names.insert(policy.name.as_str()) {
+                return Err(invalid("defined more than once".to_string()));
+            }
+            if policy.volumes.is_empty() {
+                return Err(invalid("no volume pattern".to_string()));
+            }
+            for expression in [&policy.schedule, &policy.incremental]
+                .into_iter()
+                .flatten()
+            {
+                to_cron(expression).map_err(|e| invalid(e.to_string()))?;
+            }
+            if let Some(duration) = &policy.keep_duration {
+                cutoff(duration).map_err(|e| invalid(e.to_string()))?;
+            }
+            if let Some(flags) = &policy.send_flags {
+                validate_send_flags(flags).map_err(|e| invalid(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_hooks(&self) -> Result<(), ConfigError> {
+        for hook in self.hooks.iter() {
+            if hook.volumes.is_empty() {
+                return Err(ConfigError::InvalidHook(
+                    "hook has no volume pattern".to_string(),
+                ));
+            }
+            hook.timeout()
+                .map_err(|e| ConfigError::InvalidHook(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the policy of the volumes matching no `[[backup.policy]]`
+pub const DEFAULT_POLICY: &str = "default";
+
+/// Flags of `zfs send` which can be configured. Other flags would change what is sent.
+const SEND_FLAGS: &[&str] = &[
+    "-L",
+    "--large-block",
+    "-c",
+    "--compressed",
+    "-e",
+    "--embed",
+    "-w",
+    "--raw",
+    "-p",
+    "--props",
+    "-h",
+    "--holds",
+    "-b",
+    "--backup",
+];
+
+fn validate_send_flags(flags: &[String]) -> Result<(), ConfigError> {
+    match flags
+        .iter()
+        .find(|flag| !SEND_FLAGS.contains(&flag.as_str()))
+    {
+        Some(flag) => Err(ConfigError::InvalidPolicy(format!(
+            "unsupported send flag {flag}, supported flags are {}",
+            SEND_FLAGS.join(" ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Backup settings of the volumes matching `volumes`, in place of the ones of `[backup]` and
+/// `[cleanup]`. A volume uses the first policy matching it.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VolumePolicy {
+    /// Name of the policy, used for reporting and `backup --policy`
+    pub name: String,
+    /// List of glob pattern to specify volumes
+    pub volumes: Vec<String>,
+    /// When to take full snapshots (cron expression), `backup.schedule` when not set
+    #[serde(default)]
+    schedule: Option<String>,
+    /// When to take incremental snapshots (cron expression), `backup.incremental` when not set
+    #[serde(default)]
+    incremental: Option<String>,
+    /// Flags of `zfs send`, `backup.send_flags` when not set
+    #[serde(default)]
+    send_flags: Option<Vec<String>>,
+    /// Keep at least this many full snapshots, `cleanup.keep_min` when not set
+    #[serde(default)]
+    keep_min: Option<usize>,
+    /// Keep full snapshots for this duration, `cleanup.keep_duration` when not set
+    #[serde(default)]
+    keep_duration: Option<String>,
+}
+
+/// Settings applied to the volumes of a policy, completed with the ones of `[backup]` and
+/// `[cleanup]`
+#[derive(Debug, Clone, Copy)]
+pub struct EffectivePolicy<'a> {
+    pub name: &'a str,
+    schedule: &'a str,
+    incremental: &'a str,
+    /// Flags of `zfs send`
+    pub send_flags: &'a [String],
+    /// Keep at least this many full snapshots
+    pub keep_min: usize,
+    keep_duration: &'a str,
+}
+
+impl EffectivePolicy<'_> {
+    pub fn schedule(&self) -> Result<Schedule, ConfigError> {
+        to_cron(self.schedule)
+    }
+
+    pub fn incremental(&self) -> Result<Schedule, ConfigError> {
+        to_cron(self.incremental)
+    }
+
+    /// Full snapshots older than this date can be deleted
+    pub fn keep_duration(&self) -> Result<DateTime<Utc>, ConfigError> {
+        cutoff(self.keep_duration)
+    }
+}
+
+/// A set of volumes snapshotted with a single `zfs snapshot` command so that
+/// all of them are captured at the same instant.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConsistencyGroup {
+    /// Name of the group, used for reporting
+    pub name: String,
+    /// List of glob pattern to specify the volumes of the group
+    pub volumes: Vec<String>,
+}
+
+/// Commands run for the volumes matching `volumes`.
+/// Commands are executed with `sh -c` and receive the context through environment variables.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// List of glob pattern to specify volumes
+    pub volumes: Vec<String>,
+    /// Command run before taking a snapshot, e.g. to freeze a guest filesystem
+    #[serde(default)]
+    pub pre_snapshot: Option<String>,
+    /// Command run after taking a snapshot, even if the snapshot failed
+    #[serde(default)]
+    pub post_snapshot: Option<String>,
+    /// Command run after a snapshot was uploaded to S3
+    #[serde(default)]
+    pub post_upload: Option<String>,
+    /// Command run when a snapshot or an upload failed
+    #[serde(default)]
+    pub on_failure: Option<String>,
+    /// Maximum duration of a command, e.g. "30s"
+    #[serde(default = "default_hook_timeout")]
+    timeout: String,
+    /// What to do when a command fails or times out
+    #[serde(default)]
+    pub on_error: HookFailurePolicy,
+}
+
+impl Hook {
+    pub fn timeout(&self) -> Result<std::time::Duration, ConfigError> {
+        humantime::parse_duration(&self.timeout)
+            .map_err(|e| ConfigError::InvalidDuration(e.to_string()))
+    }
+}
+
+fn default_hook_timeout() -> String {
+    "5m".to_string()
+}
+
+#[derive(Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailurePolicy {
+    /// Abort the operation, e.g. do not take the snapshot if the pre-snapshot command failed
+    #[default]
+    Abort,
+    /// Log the failure and continue
+    Continue,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CleanupPolicy {
+    /// When to run cleanup (cron expression)
+    schedule: String,
+    /// Keep at least this many full snapshots
+    pub keep_min: usize,
+    /// Keep full snapshots for this duration
+    /// When a full snapshot is deleted, all incremental snapshots
+    /// older than the full snapshot are also deleted.
+    /// E.g. "90d" for 90 days, "12w" for 12 weeks, "18m" for 18 months
+    keep_duration: String,
+    /// Snapshots to exclude from cleanup based on glob patterns
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl CleanupPolicy {
+    pub fn schedule(&self) -> Result<Schedule, ConfigError> {
+        let expression = self.schedule.as_str();
+        to_cron(expression)
+    }
+
+    pub fn keep_duration(&self) -> Result<DateTime<Utc>, ConfigError> {
+        cutoff(&self.keep_duration)
+    }
+}
+
+/// Date `duration` ago
+fn cutoff(duration: &str) -> Result<DateTime<Utc>, ConfigError> {
+    let duration = humantime::parse_duration(duration)
+        .map_err(|e| ConfigError::InvalidDuration(e.to_string()))?;
+
+    let date_time = Utc::now()
+        - chrono::Duration::from_std(duration)
+            .map_err(|e| ConfigError::InvalidDuration(e.to_string()))?;
+    Ok(date_time)
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct S3 {
+    /// S3 bucket name
+    pub bucket: String,
+    /// S3 url
+    pub url: String,
+    /// S3 region
+    pub region: String,
+    // Access key ID and secret access key are provided via environment
+    // variables and or command line args, or with the settings below.
+    /// File holding the access key ID, e.g. a systemd credential or a Docker secret
+    key_id_file: Option<PathBuf>,
+    /// File holding the secret access key
+    secret_key_file: Option<PathBuf>,
+    /// Profile of the AWS shared credentials file
+    profile: Option<String>,
+    /// AWS shared credentials file, `AWS_SHARED_CREDENTIALS_FILE` or `~/.aws/credentials` by
+    /// default
+    credentials_file: Option<PathBuf>,
+}
+
+impl S3 {
+    /// Files holding the access key ID and the secret access key
+    pub fn key_files(&self) -> Result<Option<(&Path, &Path)>, ConfigError> {
+        match (&self.key_id_file, &self.secret_key_file) {
+            (Some(key_id), Some(secret_key)) => Ok(Some((key_id, secret_key))),
+            (None, None) => Ok(None),
+            _ => Err(ConfigError::InvalidCredentials(
+                "key_id_file and secret_key_file must be set together".to_string(),
+            )),
+        }
+    }
+
+    /// Profile and the shared credentials file it is read from
+    pub fn profile(&self) -> Option<(&str, PathBuf)> {
+        let profile = self.profile.as_deref()?;
+        let path = match &self.credentials_file {
+            Some(path) => path.clone(),
+            None => match std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+                Some(path) => PathBuf::from(path),
+                None => std::env::var_os("HOME")
+                    .map(PathBuf::from)
+                    .unwrap_or_default()
+                    .join(".aws/credentials"),
+            },
+        };
+        Some((profile, path))
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct UploadPolicy {
+    /// Number of volumes uploaded at the same time. The snapshots of a volume are always
+    /// uploaded one after the other. Defaults to 1.
+    #[serde(default)]
+    max_parallel_volumes: Option<usize>,
+    /// Number of parts of a multipart upload sent at the same time. Each part is buffered
+    /// in memory: an upload uses up to `max_parallel_parts + 1` parts, of up to 500MB when the
+    /// size of the stream is unknown. Defaults to 1.
+    #[serde(default)]
+    max_parallel_parts: Option<usize>,
+    /// Upload bandwidth shared by all uploads, e.g. "50MB" per second. Unlimited by default.
+    #[serde(default)]
+    bandwidth_limit: Option<String>,
+    /// Bandwidth limits applied during a time window or to some volumes
+    #[serde(default, rename = "bandwidth")]
+    pub bandwidth: Vec<BandwidthRule>,
+    /// Cron expression of the times when the daemon uploads snapshots, e.g. "* * 0-6 * * * *"
+    /// for the night. Snapshots are uploaded at any time when not set.
+    #[serde(default)]
+    window: Option<String>,
+    /// How often the daemon looks for snapshots to upload and retries failed uploads.
+    /// Defaults to 10m.
+    #[serde(default)]
+    interval: Option<String>,
+}
+
+impl UploadPolicy {
+    pub fn max_parallel_volumes(&self) -> usize {
+        self.max_parallel_volumes.unwrap_or(1)
+    }
+
+    pub fn max_parallel_parts(&self) -> usize {
+        self.max_parallel_parts.unwrap_or(1)
+    }
+
+    pub fn bandwidth_limit(&self) -> Result<Option<u64>, ConfigError> {
+        match &self.bandwidth_limit {
+            Some(rate) => parse_rate(rate),
+            None => Ok(None),
+        }
+    }
+
+    pub fn window(&self) -> Result<Option<Schedule>, ConfigError> {
+        self.window.as_deref().map(to_window).transpose()
+    }
+
+    pub fn interval(&self) -> Result<std::time::Duration, ConfigError> {
+        let interval = parse_duration_or(self.interval.as_deref(), "10m")?;
+        if interval.is_zero() {
+            return Err(ConfigError::InvalidUpload(
+                "interval must be greater than 0".to_string(),
+            ));
+        }
+        Ok(interval)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.bandwidth_limit()?;
+        self.window()?;
+        self.interval()?;
+        for rule in self.bandwidth.iter() {
+            rule.schedule()?;
+            rule.limit()?;
+        }
+        if self.max_parallel_volumes() == 0 {
+            return Err(ConfigError::InvalidUpload(
+                "max_parallel_volumes must be at least 1".to_string(),
+            ));
+        }
+        if self.max_parallel_parts() == 0 {
+            return Err(ConfigError::InvalidUpload(
+                "max_parallel_parts must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Bandwidth limit applied while `schedule` matches, e.g. "* * 8-17 * * Mon-Fri *" for business
+/// hours. Without `volumes` the limit is shared by all uploads, otherwise it applies to each
+/// upload of the matching volumes. The first matching rule wins.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthRule {
+    /// Cron expression of the time window, the rule always applies when not set
+    #[serde(default)]
+    schedule: Option<String>,
+    /// Bytes per second, e.g. "20MB", or "unlimited"
+    limit: String,
+    /// List of glob pattern to specify the volumes
+    #[serde(default)]
+    pub volumes: Vec<String>,
+}
+
+impl BandwidthRule {
+    pub fn schedule(&self) -> Result<Option<Schedule>, ConfigError> {
+        self.schedule.as_deref().map(to_window).transpose()
+    }
+
+    /// Limit in bytes per second, none when unlimited
+    pub fn limit(&self) -> Result<Option<u64>, ConfigError> {
+        parse_rate(&self.limit)
+    }
+}
+
+/// Errors retried by the retry policy
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// Failed S3 requests: server errors, throttling, timeouts and connection errors. They are
+    /// retried by the S3 client, request by request.
+    S3,
+    /// `zfs send` failures, the snapshot is sent again
+    ZfsSend,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Number of attempts of an operation, 1 disables retries. Defaults to 3.
+    #[serde(default)]
+    attempts: Option<u32>,
+    /// Delay before the first retry, doubled after each attempt. Defaults to "1s".
+    #[serde(default)]
+    initial_backoff: Option<String>,
+    /// Maximum delay between two attempts. Defaults to "1m".
+    #[serde(default)]
+    max_backoff: Option<String>,
+    /// Randomize the delays so that retries do not happen all at once. Defaults to true.
+    #[serde(default)]
+    jitter: Option<bool>,
+    /// Errors which are retried. Defaults to all.
+    #[serde(default)]
+    retry_on: Option<Vec<RetryOn>>,
+}
+
+impl RetryPolicy {
+    pub fn attempts(&self) -> u32 {
+        self.attempts.unwrap_or(3)
+    }
+
+    pub fn initial_backoff(&self) -> Result<std::time::Duration, ConfigError> {
+        parse_duration_or(self.initial_backoff.as_deref(), "1s")
+    }
+
+    pub fn max_backoff(&self) -> Result<std::time::Duration, ConfigError> {
+        parse_duration_or(self.max_backoff.as_deref(), "1m")
+    }
+
+    pub fn jitter(&self) -> bool {
+        self.jitter.unwrap_or(true)
+    }
+
+    pub fn retries(&self, error: RetryOn) -> bool {
+        self.retry_on
+            .as_ref()
+            .is_none_or(|retry_on| retry_on.contains(&error))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.attempts() == 0 {
+            return Err(ConfigError::InvalidRetry(
+                "attempts must be at least 1".to_string(),
+            ));
+        }
+        if self.initial_backoff()? > self.max_backoff()? {
+            return Err(ConfigError::InvalidRetry(
+                "initial_backoff is greater than max_backoff".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn parse_duration_or(
+    duration: Option<&str>,
+    default: &str,
+) -> Result<std::time::Duration, ConfigError> {
+    humantime::parse_duration(duration.unwrap_or(default))
+        .map_err(|e| ConfigError::InvalidDuration(e.to_string()))
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StatePolicy {
+    /// Directory where the state of uploads is persisted.
+    /// The state is only kept in memory when not set.
+    pub dir: Option<std::path::PathBuf>,
+    /// Reuse the last listing of S3 objects if it is not older than this duration.
+    /// E.g. "1h". By default, S3 objects are listed on every sync.
+    #[serde(default)]
+    listing_max_age: Option<String>,
+}
+
+impl StatePolicy {
+    pub fn listing_max_age(&self) -> Result<chrono::Duration, ConfigError> {
+        let Some(max_age) = &self.listing_max_age else {
+            return Ok(chrono::Duration::zero());
+        };
+        let duration = humantime::parse_duration(max_age)
+            .map_err(|e| ConfigError::InvalidDuration(e.to_string()))?;
+        chrono::Duration::from_std(duration)
+            .map_err(|e| ConfigError::InvalidDuration(e.to_string()))
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    /// Address of the HTTP server in daemon mode, e.g. "127.0.0.1:9180".
+    /// The server is disabled when not set.
+    pub listen: Option<std::net::SocketAddr>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyPolicy {
+    /// Minimum interval between two notifications of the same event, e.g. "15m".
+    /// Notifications in between are dropped and counted in the next one.
+    #[serde(default)]
+    rate_limit: Option<String>,
+    /// When to send a summary of the activity (cron expression)
+    #[serde(default)]
+    summary: Option<String>,
+    /// Where to send notifications
+    #[serde(default, rename = "sink")]
+    pub sinks: Vec<Sink>,
+}
+
+impl NotifyPolicy {
+    pub fn rate_limit(&self) -> Result<std::time::Duration, ConfigError> {
+        match &self.rate_limit {
+            Some(duration) => humantime::parse_duration(duration)
+                .map_err(|e| ConfigError::InvalidDuration(e.to_string())),
+            None => Ok(std::time::Duration::ZERO),
+        }
+    }
+
+    pub fn summary(&self) -> Result<Option<Schedule>, ConfigError> {
+        self.summary.as_deref().map(to_cron).transpose()
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ShutdownPolicy {
+    /// How long uploads in progress may run after SIGTERM or SIGINT, e.g. "10m".
+    /// Uploads still running afterwards are aborted. Defaults to 5 minutes.
+    #[serde(default)]
+    grace_period: Option<String>,
+}
+
+impl ShutdownPolicy {
+    pub fn grace_period(&self) -> Result<std::time::Duration, ConfigError> {
+        match &self.grace_period {
+            Some(duration) => humantime::parse_duration(duration)
+                .map_err(|e| ConfigError::InvalidDuration(e.to_string())),
+            None => Ok(std::time::Duration::from_secs(5 * 60)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SchedulePolicy {
+    /// Run a full backup or a cleanup missed while the daemon was stopped if it was scheduled
+    /// less than this duration ago, e.g. "2d". Missed runs are skipped when not set.
+    #[serde(default)]
+    catch_up_window: Option<String>,
+    /// Maximum delay added to the scheduled backups and cleanups, e.g. "10m", so that hosts
+    /// sharing a configuration do not start at the same time
+    #[serde(default)]
+    jitter: Option<String>,
+    /// How the delay is chosen
+    #[serde(default)]
+    pub jitter_mode: JitterMode,
+    /// Time zone of the schedules, e.g. "Europe/Paris". Defaults to UTC.
+    #[serde(default)]
+    timezone: Option<String>,
+}
+
+/// How the delay of the scheduled runs is chosen
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JitterMode {
+    /// A new random delay for every run
+    #[default]
+    Random,
+    /// The same delay for every run of a host, derived from its hostname
+    Hostname,
+}
+
+impl SchedulePolicy {
+    pub fn timezone(&self) -> Result<Tz, ConfigError> {
+        match &self.timezone {
+            Some(timezone) => timezone
+                .parse()
+                .map_err(|_| ConfigError::InvalidTimezone(timezone.clone())),
+            None => Ok(Tz::UTC),
+        }
+    }
+
+    pub fn jitter(&self) -> Result<std::time::Duration, ConfigError> {
+        parse_duration_or(self.jitter.as_deref(), "0s")
+    }
+
+    pub fn catch_up_window(&self) -> Result<Option<chrono::Duration>, ConfigError> {
+        let Some(window) = &self.catch_up_window else {
+            return Ok(None);
+        };
+        let duration = humantime::parse_duration(window)
+            .map_err(|e| ConfigError::InvalidDuration(e.to_string()))?;
+        chrono::Duration::from_std(duration)
+            .map(Some)
+            .map_err(|e| ConfigError::InvalidDuration(e.to_string()))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Sink {
+    #[serde(flatten)]
+    pub kind: SinkKind,
+    /// Events sent to this sink, all events when empty
+    #[serde(default)]
+    pub events: Vec<NotificationEvent>,
+    /// Title template. Placeholders: {hostname}, {event}, {message}
+    #[serde(default = "default_notification_title")]
+    pub title: String,
+    /// Message template. Placeholders: {hostname}, {event}, {message}
+    #[serde(default = "default_notification_message")]
+    pub message: String,
+}
+
+fn default_notification_title() -> String {
+    "zfs2s3 {event} on {hostname}".to_string()
+}
+
+fn default_notification_message() -> String {
+    "{message}".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+/// Unknown keys of a sink are rejected here, `Sink` flattens the kind
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SinkKind {
+    /// Generic JSON payload with event, hostname, title, message and timestamp
+    Webhook {
+        url: String,
+    },
+    Slack {
+        url: String,
+    },
+    Discord {
+        url: String,
+    },
+    Gotify {
+        url: String,
+        token: String,
+    },
+    /// `url` is the topic URL, e.g. "https://ntfy.sh/my-topic"
+    Ntfy {
+        url: String,
+        token: Option<String>,
+    },
+    Smtp {
+        host: String,
+        port: Option<u16>,
+        #[serde(default)]
+        tls: SmtpTls,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    #[default]
+    Starttls,
+    Tls,
+    None,
+}
+
+/// Parse a rate in bytes per second such as "500KB", "20MB" or "1GiB". "unlimited" and "0"
+/// disable the limit.
+fn parse_rate(rate: &str) -> Result<Option<u64>, ConfigError> {
+    let rate = rate.trim();
+    if rate.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+    let split = rate
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rate.len());
+    let (value, unit) = rate.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().trim_end_matches("/s") {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => return Err(ConfigError::InvalidUpload(format!("invalid rate: {rate}"))),
+    };
+    let value: f64 = value
+        .parse()
+        .map_err(|_| ConfigError::InvalidUpload(format!("invalid rate: {rate}")))?;
+    let bytes = (value * multiplier as f64) as u64;
+    Ok((bytes > 0).then_some(bytes))
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` in the string values of a configuration with the
+/// value of the environment variables, `$${` being a literal `${`. The results are written back
+/// as TOML strings, so that a value cannot change the structure of the file.
+fn interpolate(toml: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, ConfigError> {
+    let table = toml::de::DeTable::parse(toml).map_err(|e| toml_error(toml, e))?;
+    let mut strings = Vec::new();
+    collect_strings(table.get_ref(), &mut strings);
+    strings.sort_by_key(|(span, _)| span.start);
+
+    let mut output = toml.to_string();
+    for (span, value) in strings.into_iter().rev() {
+        if !value.contains('$') {
+            continue;
+        }
+        let line = toml[..span.start].matches('\n').count() + 1;
+        let interpolated = interpolate_string(value, &lookup)
+            .map_err(|e| ConfigError::InvalidInterpolation(format!("line {line}: {e}")))?;
+        if interpolated != value {
+            output.replace_range(span, &toml::Value::String(interpolated).to_string());
+        }
+    }
+    Ok(output)
+}
+
+/// String values of a table, with their span in the file
+fn collect_strings<'a>(
+    table: &'a toml::de::DeTable<'_>,
+    strings: &mut Vec<(std::ops::Range<usize>, &'a str)>,
+) {
+    fn collect_value<'a>(
+        value: &'a toml::Spanned<toml::de::DeValue<'_>>,
+        strings: &mut Vec<(std::ops::Range<usize>, &'a str)>,
+    ) {
+        match value.get_ref() {
+            toml::de::DeValue::String(string) => strings.push((value.span(), string)),
+            toml::de::DeValue::Array(array) => {
+                array.iter().for_each(|value| collect_value(value, strings))
+            }
+            toml::de::DeValue::Table(table) => collect_strings(table, strings),
+            _ => {}
+        }
+    }
+    table
+        .values()
+        .for_each(|value| collect_value(value, strings));
+}
+
+fn interpolate_string(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, String> {
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix("${") {
+            output.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(expression) = rest.strip_prefix('{') else {
+            output.push('$');
+            continue;
+        };
+        let end = expression
+            .find('}')
+            .ok_or_else(|| "unterminated ${".to_string())?;
+        let (name, default) = match expression[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&expression[..end], None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid variable name `{name}`"));
+        }
+        match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => output.push_str(default),
+            (Some(value), _) => output.push_str(&value),
+            (None, Some(default)) => output.push_str(default),
+            (None, None) => return Err(format!("variable {name} is not set")),
+        }
+        rest = &expression[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+async fn read_config(path: &Path) -> Result<String, ConfigError> {
+    tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| ConfigError::InvalidInclude(format!("failed to read {}: {e}", path.display())))
+}
+
+/// Files matching the globs, relative to `base`, sorted for each glob. A path without wildcards
+/// must exist.
+async fn included_files(base: &Path, patterns: &[String]) -> Result<Vec<PathBuf>, ConfigError> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let pattern = base.join(pattern);
+        if !pattern.to_string_lossy().contains(['*', '?', '[', '{']) {
+            if !pattern.is_file() {
+                return Err(ConfigError::InvalidInclude(format!(
+                    "{} does not exist",
+                    pattern.display()
+                )));
+            }
+            files.push(pattern);
+            continue;
+        }
+
+        // Walk the directories below the part of the glob without wildcards
+        let root: PathBuf = pattern
+            .components()
+            .take_while(|c| {
+                !c.as_os_str()
+                    .to_string_lossy()
+                    .contains(['*', '?', '[', '{'])
+            })
+            .collect();
+        let glob = pattern.to_string_lossy();
+        let mut matches = Vec::new();
+        let mut directories = vec![root];
+        while let Some(directory) = directories.pop() {
+            let Ok(mut entries) = tokio::fs::read_dir(&directory).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                if path.is_dir() {
+                    directories.push(path);
+                } else if fast_glob::glob_match(glob.as_ref(), path.to_string_lossy().as_ref()) {
+                    matches.push(path);
+                }
+            }
+        }
+        matches.sort();
+        files.extend(matches);
+    }
+    Ok(files)
+}
+
+/// Merge a fragment into a configuration: tables are merged, arrays extended and other values
+/// replaced
+fn merge(table: &mut toml::Table, fragment: toml::Table) {
+    for (key, value) in fragment {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(fragment)) => {
+                merge(table, fragment)
+            }
+            (Some(toml::Value::Array(array)), toml::Value::Array(fragment)) => {
+                array.extend(fragment)
+            }
+            (Some(existing), value) => *existing = value,
+            (None, value) => {
+                table.insert(key, value);
+            }
+        }
     }
 }
 
-#[derive(Debug, Deserialize, Default)]
-pub struct CleanupPolicy {
-    /// When to run cleanup (cron expression)
-    schedule: String,
-    /// Keep at least this many full snapshots
-    pub keep_min: usize,
-    /// Keep full snapshots for this duration
-    /// When a full snapshot is deleted, all incremental snapshots
-    /// older than the full snapshot are also deleted.
-    /// E.g. "90d" for 90 days, "12w" for 12 weeks, "18m" for 18 months
-    keep_duration: String,
-    /// Snapshots to exclude from cleanup based on glob patterns
-    #[serde(default)]
-    pub exclude: Vec<String>,
+/// Syntax error, with the section where it is
+fn toml_error(toml: &str, e: toml::de::Error) -> ConfigError {
+    let section = e.span().and_then(|span| section_at(toml, span.start));
+    ConfigError::InvalidToml(match section {
+        Some(section) => format!("in section [{section}]\n{e}"),
+        None => e.to_string(),
+    })
 }
 
-impl CleanupPolicy {
-    pub fn schedule(&self) -> Result<Schedule, ConfigError> {
-        let expression = self.schedule.as_str();
-        to_cron(expression)
-    }
+/// Name the key of a validation error
+fn at<T>(key: &str, result: Result<T, ConfigError>) -> Result<T, ConfigError> {
+    result.map_err(|error| ConfigError::InvalidKey {
+        key: key.to_string(),
+        file: None,
+        location: None,
+        error: Box::new(error),
+    })
+}
 
-    pub fn keep_duration(&self) -> Result<DateTime<Utc>, ConfigError> {
-        let duration = humantime::parse_duration(&self.keep_duration)
-            .map_err(|e| ConfigError::InvalidDuration(e.to_string()))?;
+/// Key of a deserialization error, e.g. `backup.incremnetal` for an unknown field of `[backup]`,
+/// from the path the error is reported in
+fn error_key(e: &toml::de::Error) -> Option<String> {
+    let text = e.to_string();
+    let path = text
+        .lines()
+        .last()
+        .and_then(|line| line.strip_prefix("in `"))
+        .and_then(|line| line.strip_suffix('`'));
+    let field = e
+        .message()
+        .strip_prefix("unknown field `")
+        .and_then(|message| message.split_once('`'))
+        .map(|(field, _)| field);
+    match (path, field) {
+        (Some(path), Some(field)) => Some(format!("{path}.{field}")),
+        (Some(path), None) => Some(path.to_string()),
+        (None, Some(field)) => Some(field.to_string()),
+        (None, None) => None,
+    }
+}
 
-        let date_time = Utc::now()
-            - chrono::Duration::from_std(duration)
-                .map_err(|e| ConfigError::InvalidDuration(e.to_string()))?;
-        Ok(date_time)
+/// Line and column, starting at 1, of the value of a key such as `backup.schedule`. Keys
+/// which are not in the file, e.g. defaults, have no location.
+fn locate(toml: &str, key: &str) -> Option<(usize, usize)> {
+    let table = toml::de::DeTable::parse(toml).ok()?;
+    let mut value = toml::Spanned::new(table.span(), toml::de::DeValue::Table(table.into_inner()));
+    for part in key.split('.') {
+        value = value.as_ref().get(part)?.clone();
+        // Arrays of tables, e.g. `[[backup.group]]`, are located at their first entry
+        if let Some(first) = value.as_ref().as_array().and_then(|a| a.first())
+            && first.as_ref().is_table()
+        {
+            value = first.clone();
+        }
     }
+
+    let start = value.span().start;
+    let line_start = toml[..start].rfind('\n').map_or(0, |i| i + 1);
+    Some((
+        toml[..start].matches('\n').count() + 1,
+        toml[line_start..start].chars().count() + 1,
+    ))
 }
 
-#[derive(Debug, Deserialize, Default)]
-pub struct S3 {
-    /// S3 bucket name
-    pub bucket: String,
-    /// S3 url
-    pub url: String,
-    /// S3 region
-    pub region: String,
-    // Access key ID and secret access key are provided via environment
-    // variables and or command line args.
+/// Name of the table, e.g. `backup` or `upload.bandwidth`, defining the byte at `offset`. None
+/// for the keys at the top of the file.
+fn section_at(toml: &str, offset: usize) -> Option<String> {
+    let mut section = None;
+    let mut line_start = 0;
+    for line in toml.split_inclusive('\n') {
+        if line_start > offset {
+            break;
+        }
+        let header = line.trim();
+        if header.starts_with('[') {
+            section = Some(
+                header
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .trim()
+                    .to_string(),
+            );
+        }
+        line_start += line.len();
+    }
+    section
 }
 
 fn to_cron(expression: &str) -> Result<Schedule, ConfigError> {
@@ -140,9 +1367,221 @@ fn to_cron(expression: &str) -> Result<Schedule, ConfigError> {
         .map_err(|_| ConfigError::InvalidCronExpression(expression.to_string()))
 }
 
+/// Cron expression of a time window, which is open while the current time matches it. The
+/// seconds must be `*`, otherwise the window would close a second after opening.
+fn to_window(expression: &str) -> Result<Schedule, ConfigError> {
+    let schedule = to_cron(expression)?;
+    if expression.split_whitespace().next() != Some("*") {
+        return Err(ConfigError::InvalidUpload(format!(
+            "time window {expression} must use `*` for the seconds, e.g. \"* * 0-6 * * * *\""
+        )));
+    }
+    Ok(schedule)
+}
+
 #[cfg(test)]
 mod test_config {
     use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn unknown_keys_rejected() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 0 15 * * *"
+incremental = "0 4 * 14 * * *"
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "90d"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+
+[upload]
+max_parallel_volumes = 2
+max_parralel_parts = 4
+"#;
+        let error = Config::try_from(CONFIG).unwrap_err().to_string();
+        assert!(error.contains("in section [upload]"), "{error}");
+        assert!(error.contains("line 18"), "{error}");
+        assert!(
+            error.contains("unknown field `max_parralel_parts`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn invalid_value_located() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 0 15 * * *"
+incremental = "0 4 * 14"
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "90d"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+        let error = Config::try_from(CONFIG)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        assert_eq!(
+            *error,
+            ConfigError::InvalidKey {
+                key: "backup.incremental".to_string(),
+                file: None,
+                location: Some((4, 15)),
+                error: Box::new(ConfigError::InvalidCronExpression("0 4 * 14".to_string())),
+            }
+        );
+
+        let newer = format!("version = {}\n{CONFIG}", CONFIG_VERSION + 1);
+        assert!(
+            Config::try_from(&newer)
+                .unwrap_err()
+                .to_string()
+                .contains("Unsupported configuration version")
+        );
+    }
+
+    #[test]
+    fn variables_interpolated() {
+        let lookup = |name: &str| match name {
+            "BUCKET" => Some("host-1".to_string()),
+            "EMPTY" => Some(String::new()),
+            "TOKEN" => Some("a\"b\\c\n[s3]".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            interpolate(
+                "# ${UNSET}\nbucket = \"${BUCKET}-${EMPTY:-x}\"\nurl = \"${URL:-http://s3}/$${BUCKET}\"",
+                lookup
+            )
+            .unwrap(),
+            "# ${UNSET}\nbucket = \"host-1-x\"\nurl = \"http://s3/${BUCKET}\""
+        );
+        assert_eq!(
+            interpolate("a = 1\nb = \"${UNSET}\"", lookup).unwrap_err(),
+            ConfigError::InvalidInterpolation("line 2: variable UNSET is not set".to_string())
+        );
+        assert!(interpolate("b = \"${BUCKET\"", lookup).is_err());
+
+        // Values cannot break out of their string, comments are left as is
+        let toml = interpolate("token = \"${TOKEN}\" # ${UNSET}\nport = 1", lookup).unwrap();
+        let table: toml::Table = toml::from_str(&toml).unwrap();
+        assert_eq!(table["token"].as_str(), Some("a\"b\\c\n[s3]"));
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn policies_completed_with_defaults() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 0 * * Sun *"
+incremental = "0 0 4 * * * *"
+volumes = ["pool/*"]
+send_flags = ["--raw"]
+
+[[backup.policy]]
+name = "databases"
+volumes = ["pool/vm-1*"]
+incremental = "0 0 * * * * *"
+keep_duration = "14d"
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "90d"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+        let config = Config::try_from(CONFIG).unwrap();
+        let policies = config.policies();
+        assert_eq!(policies.len(), 2);
+        assert_eq!(policies[0].name, DEFAULT_POLICY);
+
+        let databases = config.policy("databases");
+        assert_eq!(databases.name, "databases");
+        assert_eq!(databases.schedule().unwrap().source(), "0 0 0 * * Sun *");
+        assert_eq!(databases.incremental().unwrap().source(), "0 0 * * * * *");
+        assert_eq!(databases.send_flags, ["--raw"]);
+        assert_eq!(databases.keep_min, 3);
+        assert!(databases.keep_duration().unwrap() > policies[0].keep_duration().unwrap());
+        assert_eq!(config.policy("missing").name, DEFAULT_POLICY);
+
+        let invalid = CONFIG.replace("keep_duration = \"14d\"", "send_flags = [\"-i\"]");
+        let error = Config::try_from(&invalid).unwrap_err().to_string();
+        assert!(error.contains("unsupported send flag -i"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn includes_merged() {
+        const CONFIG: &str = r#"
+include = ["conf.d/*.toml"]
+
+[backup]
+schedule = "0 0 0 15 * * *"
+incremental = "0 0 4 * * * *"
+volumes = ["pool/vm-100-*"]
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "90d"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+        let dir = std::env::temp_dir().join(format!("zfs2s3-config-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        std::fs::write(dir.join("config.toml"), CONFIG).unwrap();
+        std::fs::write(
+            dir.join("conf.d/10-volumes.toml"),
+            "[backup]\nvolumes = [\"pool/vm-200-*\"]\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("conf.d/20-s3.toml"), "[s3]\nbucket = \"host-1\"\n").unwrap();
+
+        let config = Config::load(&dir.join("config.toml")).await.unwrap();
+        assert_eq!(config.backup.volumes, ["pool/vm-100-*", "pool/vm-200-*"]);
+        assert_eq!(config.s3.bucket, "host-1");
+        assert_eq!(config.s3.region, "garage");
+
+        std::fs::write(
+            dir.join("conf.d/30-nested.toml"),
+            "include = [\"x.toml\"]\n",
+        )
+        .unwrap();
+        assert!(Config::load(&dir.join("config.toml")).await.is_err());
+
+        // Errors name the included file the key comes from
+        std::fs::remove_file(dir.join("conf.d/30-nested.toml")).unwrap();
+        let included = dir.join("conf.d/40-invalid.toml");
+        std::fs::write(&included, "[upload]\nmax_parallel_volumes = 0\n").unwrap();
+        let error = Config::load(&dir.join("config.toml")).await.unwrap_err();
+        let expected = format!("`upload` in {} at line 1, column 1", included.display());
+        assert!(error.to_string().contains(&expected), "{error}");
+        std::fs::write(&included, "[backup]\nincremnetal = \"x\"\n").unwrap();
+        let error = Config::load(&dir.join("config.toml")).await.unwrap_err();
+        let expected = format!("in {} at line 2, column 15", included.display());
+        assert!(error.to_string().contains(&expected), "{error}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
     #[test]
     fn invalid_config_wrong_cron() {
@@ -229,4 +1668,203 @@ region = "garage"
         let config = Config::try_from(CONFIG);
         assert!(config.is_ok());
     }
+
+    #[test]
+    fn valid_config_with_groups() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 5 * * Sun *"
+incremental = "0 30 4 * * Mon-Sat *"
+volumes = ["zfs2s3/vm-*"]
+
+[[backup.group]]
+name = "database"
+volumes = ["zfs2s3/vm-100-disk-0", "zfs2s3/vm-100-disk-1"]
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "3 months"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+
+        let config = Config::try_from(CONFIG).unwrap();
+        assert_eq!(config.backup.groups.len(), 1);
+        assert_eq!(config.backup.groups[0].name, "database");
+    }
+
+    #[test]
+    fn valid_config_with_hooks() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 5 * * Sun *"
+incremental = "0 30 4 * * Mon-Sat *"
+volumes = ["zfs2s3/vm-*"]
+
+[[backup.hook]]
+volumes = ["zfs2s3/vm-100-*"]
+pre_snapshot = "qm guest cmd 100 fsfreeze-freeze"
+post_snapshot = "qm guest cmd 100 fsfreeze-thaw"
+timeout = "30s"
+on_error = "continue"
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "3 months"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+
+        let config = Config::try_from(CONFIG).unwrap();
+        let hook = &config.backup.hooks[0];
+        assert_eq!(hook.on_error, HookFailurePolicy::Continue);
+        assert_eq!(hook.timeout().unwrap(), std::time::Duration::from_secs(30));
+        assert!(hook.post_upload.is_none());
+    }
+
+    #[test]
+    fn invalid_config_duplicate_group() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 5 * * Sun *"
+incremental = "0 30 4 * * Mon-Sat *"
+
+[[backup.group]]
+name = "database"
+volumes = ["zfs2s3/vm-100-disk-0"]
+
+[[backup.group]]
+name = "database"
+volumes = ["zfs2s3/vm-100-disk-1"]
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "3 months"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+
+        let config = Config::try_from(CONFIG);
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn invalid_config_zero_parallel_volumes() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 5 * * Sun *"
+incremental = "0 30 4 * * Mon-Sat *"
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "3 months"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+
+[upload]
+max_parallel_volumes = 0
+"#;
+
+        let config = Config::try_from(CONFIG);
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn upload_window() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 5 * * Sun *"
+incremental = "0 30 4 * * Mon-Sat *"
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "3 months"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+
+[upload]
+window = "* * 0-6 * * * *"
+interval = "5m"
+"#;
+
+        let config = Config::try_from(CONFIG).unwrap();
+        let window = config.upload.window().unwrap().unwrap();
+        assert!(window.includes(Utc.with_ymd_and_hms(2025, 6, 1, 3, 0, 0).unwrap()));
+        assert!(!window.includes(Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap()));
+        assert_eq!(
+            config.upload.interval(),
+            Ok(std::time::Duration::from_secs(300))
+        );
+        assert!(
+            config
+                .schedules()
+                .unwrap()
+                .iter()
+                .any(|(key, _)| key == "upload.window")
+        );
+
+        let config = Config::try_from(CONFIG.replace("\"5m\"", "\"0s\"").as_str());
+        assert!(config.is_err());
+
+        // A window matching a single second closes right after opening
+        let config = Config::try_from(CONFIG.replace("\"* * 0-6", "\"0 * 0-6").as_str());
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn catch_up_requires_state_dir() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 5 * * Sun *"
+incremental = "0 30 4 * * Mon-Sat *"
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "3 months"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+
+[schedule]
+catch_up_window = "2d"
+"#;
+
+        // Missed runs are only known when the state is persisted
+        let error = Config::try_from(CONFIG).unwrap_err().to_string();
+        assert!(error.contains("state.dir"), "{error}");
+        let persisted = format!("{CONFIG}\n[state]\ndir = \"/var/lib/zfs2s3\"\n");
+        assert!(Config::try_from(&persisted).is_ok());
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("20MB"), Ok(Some(20_000_000)));
+        assert_eq!(parse_rate("1.5 KiB/s"), Ok(Some(1536)));
+        assert_eq!(parse_rate("unlimited"), Ok(None));
+        assert_eq!(parse_rate("0"), Ok(None));
+        assert!(parse_rate("20 parsecs").is_err());
+    }
 }
@@ -0,0 +1,263 @@
+/// Sources of the S3 credentials. Files and profiles are read again every time the credentials
+/// are needed, so that rotated credentials are used without a restart.
+use crate::config::{ConfigError, S3};
+use async_trait::async_trait;
+use object_store::CredentialProvider;
+use object_store::StaticCredentialProvider;
+use object_store::aws::{AwsCredential, AwsCredentialProvider};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Key ID and secret key from the command line or the environment
+    Static { key_id: String, secret_key: String },
+    /// Files holding the key ID and the secret key
+    Files {
+        key_id: PathBuf,
+        secret_key: PathBuf,
+    },
+    /// Profile of an AWS shared credentials file
+    Profile { name: String, path: PathBuf },
+    /// Credential chain of the S3 client: the `AWS_*` environment variables, web identity
+    /// tokens, then the ECS or EC2 instance metadata
+    Chain,
+}
+
+impl Credentials {
+    /// Pick the source of the credentials, from the first of: the command line or environment,
+    /// the key files of `[s3]`, the profile of `[s3]` and the credential chain.
+    pub fn resolve(
+        key_id: Option<&str>,
+        secret_key: Option<&str>,
+        config: &S3,
+    ) -> Result<Self, ConfigError> {
+        match (key_id, secret_key) {
+            (Some(key_id), Some(secret_key)) => {
+                return Ok(Credentials::Static {
+                    key_id: key_id.to_string(),
+                    secret_key: secret_key.to_string(),
+                });
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConfigError::InvalidCredentials(
+                    "S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY must be set together".to_string(),
+                ));
+            }
+        }
+
+        if let Some((key_id, secret_key)) = config.key_files()? {
+            return Ok(Credentials::Files {
+                key_id: key_id.to_path_buf(),
+                secret_key: secret_key.to_path_buf(),
+            });
+        }
+        if let Some((name, path)) = config.profile() {
+            return Ok(Credentials::Profile {
+                name: name.to_string(),
+                path,
+            });
+        }
+        Ok(Credentials::Chain)
+    }
+
+    /// Provider of the credentials, none for the credential chain of the S3 client
+    pub fn provider(&self) -> Option<AwsCredentialProvider> {
+        match self.clone() {
+            Credentials::Static { key_id, secret_key } => {
+                Some(Arc::new(StaticCredentialProvider::new(AwsCredential {
+                    key_id,
+                    secret_key,
+                    token: None,
+                })))
+            }
+            Credentials::Files { key_id, secret_key } => {
+                Some(Arc::new(FileCredentialProvider { key_id, secret_key }))
+            }
+            Credentials::Profile { name, path } => {
+                Some(Arc::new(ProfileCredentialProvider { name, path }))
+            }
+            Credentials::Chain => None,
+        }
+    }
+}
+
+/// Describes the source, never the secrets
+impl Display for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Static { .. } => write!(f, "the command line or environment"),
+            Credentials::Files { key_id, secret_key } => {
+                write!(f, "files {} and {}", key_id.display(), secret_key.display())
+            }
+            Credentials::Profile { name, path } => {
+                write!(f, "profile {name} of {}", path.display())
+            }
+            Credentials::Chain => write!(f, "the AWS credential chain"),
+        }
+    }
+}
+
+fn unauthenticated(path: &Path, message: String) -> object_store::Error {
+    object_store::Error::Unauthenticated {
+        path: path.display().to_string(),
+        source: message.into(),
+    }
+}
+
+async fn read_file(path: &Path) -> object_store::Result<String> {
+    tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| unauthenticated(path, format!("Failed to read {}: {e}", path.display())))
+}
+
+#[derive(Debug)]
+struct FileCredentialProvider {
+    key_id: PathBuf,
+    secret_key: PathBuf,
+}
+
+#[async_trait]
+impl CredentialProvider for FileCredentialProvider {
+    type Credential = AwsCredential;
+
+    async fn get_credential(&self) -> object_store::Result<Arc<AwsCredential>> {
+        Ok(Arc::new(AwsCredential {
+            key_id: read_file(&self.key_id).await?.trim().to_string(),
+            secret_key: read_file(&self.secret_key).await?.trim().to_string(),
+            token: None,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct ProfileCredentialProvider {
+    name: String,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl CredentialProvider for ProfileCredentialProvider {
+    type Credential = AwsCredential;
+
+    async fn get_credential(&self) -> object_store::Result<Arc<AwsCredential>> {
+        let content = read_file(&self.path).await?;
+        parse_profile(&content, &self.name)
+            .map(Arc::new)
+            .ok_or_else(|| {
+                unauthenticated(
+                    &self.path,
+                    format!(
+                        "No credentials for profile {} in {}",
+                        self.name,
+                        self.path.display()
+                    ),
+                )
+            })
+    }
+}
+
+/// Credentials of a profile in the INI format of the AWS shared credentials file. Sections
+/// are named `[name]`, or `[profile name]` as in `~/.aws/config`.
+fn parse_profile(content: &str, name: &str) -> Option<AwsCredential> {
+    let mut in_profile = false;
+    let (mut key_id, mut secret_key, mut token) = (None, None, None);
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let section = section.trim();
+            in_profile = section == name || section.strip_prefix("profile ") == Some(name);
+            continue;
+        }
+        if !in_profile {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = Some(value.trim().to_string());
+            match key.trim() {
+                "aws_access_key_id" => key_id = value,
+                "aws_secret_access_key" => secret_key = value,
+                "aws_session_token" => token = value,
+                _ => {}
+            }
+        }
+    }
+
+    Some(AwsCredential {
+        key_id: key_id?,
+        secret_key: secret_key?,
+        token,
+    })
+}
+
+#[cfg(test)]
+mod test_credentials {
+    use super::*;
+
+    fn s3(toml: &str) -> S3 {
+        toml::from_str(&format!(
+            "bucket = \"b\"\nurl = \"u\"\nregion = \"r\"\n{toml}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn resolve_in_order() {
+        let config = s3(
+            "key_id_file = \"/run/key_id\"\nsecret_key_file = \"/run/secret\"\nprofile = \"backup\"",
+        );
+        assert!(matches!(
+            Credentials::resolve(Some("id"), Some("secret"), &config),
+            Ok(Credentials::Static { .. })
+        ));
+        assert!(Credentials::resolve(Some("id"), None, &config).is_err());
+        assert_eq!(
+            Credentials::resolve(None, None, &config).unwrap(),
+            Credentials::Files {
+                key_id: PathBuf::from("/run/key_id"),
+                secret_key: PathBuf::from("/run/secret"),
+            }
+        );
+
+        let config = s3("profile = \"backup\"\ncredentials_file = \"/etc/aws\"");
+        assert_eq!(
+            Credentials::resolve(None, None, &config).unwrap(),
+            Credentials::Profile {
+                name: "backup".to_string(),
+                path: PathBuf::from("/etc/aws"),
+            }
+        );
+        assert_eq!(
+            Credentials::resolve(None, None, &s3("")).unwrap(),
+            Credentials::Chain
+        );
+    }
+
+    #[test]
+    fn profile_parsed() {
+        const CREDENTIALS: &str = r#"
+[default]
+aws_access_key_id = default-id
+aws_secret_access_key = default-secret
+
+# Backups
+[profile backup]
+aws_access_key_id = backup-id
+aws_secret_access_key = backup-secret
+aws_session_token = backup-token
+"#;
+        let credential = parse_profile(CREDENTIALS, "backup").unwrap();
+        assert_eq!(credential.key_id, "backup-id");
+        assert_eq!(credential.secret_key, "backup-secret");
+        assert_eq!(credential.token.as_deref(), Some("backup-token"));
+        assert_eq!(
+            parse_profile(CREDENTIALS, "default").unwrap().key_id,
+            "default-id"
+        );
+        assert!(parse_profile(CREDENTIALS, "missing").is_none());
+    }
+}
@@ -0,0 +1,193 @@
+/// User defined commands run around snapshots and uploads, e.g. to freeze a guest
+/// filesystem before a snapshot and thaw it afterwards.
+use crate::SnapshotType;
+use crate::config::{Hook, HookFailurePolicy};
+use fast_glob::glob_match;
+use std::fmt::{Display, Formatter};
+use tokio::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    PreSnapshot,
+    PostSnapshot,
+    PostUpload,
+    OnFailure,
+}
+
+impl Display for HookEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookEvent::PreSnapshot => write!(f, "pre-snapshot"),
+            HookEvent::PostSnapshot => write!(f, "post-snapshot"),
+            HookEvent::PostUpload => write!(f, "post-upload"),
+            HookEvent::OnFailure => write!(f, "on-failure"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HookError {
+    Failed(String, String),
+    Timeout(String),
+}
+
+impl Display for HookError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookError::Failed(cmd, e) => write!(f, "Hook `{}` failed: {}", cmd, e),
+            HookError::Timeout(cmd) => write!(f, "Hook `{}` timed out", cmd),
+        }
+    }
+}
+
+impl std::error::Error for HookError {}
+
+/// Description of what the hook is run for, exposed to the command as environment variables:
+/// - `ZFS2S3_EVENT`: pre-snapshot, post-snapshot, post-upload or on-failure
+/// - `ZFS2S3_VOLUMES`: space separated list of volumes
+/// - `ZFS2S3_SNAPSHOTS`: space separated list of snapshots
+/// - `ZFS2S3_SNAPSHOT_TYPE`: full or incremental
+/// - `ZFS2S3_GROUP`: consistency group name, empty if none
+/// - `ZFS2S3_ERROR`: error message, only set for on-failure
+pub struct HookContext<'a> {
+    pub volumes: &'a [String],
+    pub snapshots: &'a [String],
+    pub snapshot_type: &'a SnapshotType,
+    pub group: Option<&'a str>,
+    pub error: Option<&'a str>,
+}
+
+impl Hook {
+    fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::PreSnapshot => self.pre_snapshot.as_deref(),
+            HookEvent::PostSnapshot => self.post_snapshot.as_deref(),
+            HookEvent::PostUpload => self.post_upload.as_deref(),
+            HookEvent::OnFailure => self.on_failure.as_deref(),
+        }
+    }
+
+    fn applies_to(&self, volumes: &[String]) -> bool {
+        volumes
+            .iter()
+            .any(|v| self.volumes.iter().any(|pattern| glob_match(pattern, v)))
+    }
+}
+
+/// Run the commands of all hooks matching the context volumes for the given event,
+/// in configuration order.
+/// Returns an error as soon as a command with the abort policy fails.
+pub async fn run_hooks(
+    hooks: &[Hook],
+    event: HookEvent,
+    ctx: &HookContext<'_>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    for hook in hooks.iter().filter(|h| h.applies_to(ctx.volumes)) {
+        let Some(command) = hook.command(event) else {
+            continue;
+        };
+
+        log::info!("Running {event} hook `{command}`");
+        if let Err(e) = run_command(hook, event, command, ctx).await {
+            match hook.on_error {
+                HookFailurePolicy::Abort => return Err(e),
+                HookFailurePolicy::Continue => log::warn!("{e}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_command(
+    hook: &Hook,
+    event: HookEvent,
+    command: &str,
+    ctx: &HookContext<'_>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("ZFS2S3_EVENT", event.to_string())
+        .env("ZFS2S3_VOLUMES", ctx.volumes.join(" "))
+        .env("ZFS2S3_SNAPSHOTS", ctx.snapshots.join(" "))
+        .env("ZFS2S3_SNAPSHOT_TYPE", ctx.snapshot_type.to_string())
+        .env("ZFS2S3_GROUP", ctx.group.unwrap_or_default())
+        .kill_on_drop(true);
+    if let Some(error) = ctx.error {
+        cmd.env("ZFS2S3_ERROR", error);
+    }
+
+    let output = tokio::time::timeout(hook.timeout()?, cmd.output())
+        .await
+        .map_err(|_| HookError::Timeout(command.to_string()))??;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Err(HookError::Failed(command.to_string(), format!("{} {stderr}", output.status)).into())
+    }
+}
+
+#[cfg(test)]
+mod test_hooks {
+    use super::*;
+
+    fn hook(pre_snapshot: &str, on_error: &str) -> Hook {
+        let toml = format!(
+            "volumes = [\"pool/vm-100-*\"]\npre_snapshot = '{pre_snapshot}'\ntimeout = \"1s\"\non_error = \"{on_error}\""
+        );
+        toml::from_str(&toml).unwrap()
+    }
+
+    fn context<'a>(volumes: &'a [String]) -> HookContext<'a> {
+        HookContext {
+            volumes,
+            snapshots: &[],
+            snapshot_type: &SnapshotType::Full,
+            group: None,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn abort_on_failure() {
+        let volumes = vec!["pool/vm-100-disk-0".to_string()];
+        let hooks = vec![hook("exit 1", "abort")];
+        let result = run_hooks(&hooks, HookEvent::PreSnapshot, &context(&volumes)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn continue_on_failure() {
+        let volumes = vec!["pool/vm-100-disk-0".to_string()];
+        let hooks = vec![hook("exit 1", "continue")];
+        let result = run_hooks(&hooks, HookEvent::PreSnapshot, &context(&volumes)).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let volumes = vec!["pool/vm-100-disk-0".to_string()];
+        let hooks = vec![hook("sleep 5", "abort")];
+        let result = run_hooks(&hooks, HookEvent::PreSnapshot, &context(&volumes)).await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn environment_and_matching() {
+        let volumes = vec!["pool/vm-101-disk-0".to_string()];
+        let hooks = vec![hook("exit 1", "abort")];
+        // Hook does not apply to this volume
+        let result = run_hooks(&hooks, HookEvent::PreSnapshot, &context(&volumes)).await;
+        assert!(result.is_ok());
+
+        let volumes = vec!["pool/vm-100-disk-0".to_string()];
+        let hooks = vec![hook(
+            r#"test "$ZFS2S3_VOLUMES" = pool/vm-100-disk-0 -a "$ZFS2S3_EVENT" = pre-snapshot"#,
+            "abort",
+        )];
+        let result = run_hooks(&hooks, HookEvent::PreSnapshot, &context(&volumes)).await;
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,149 @@
+/// Lightweight HTTP server for the daemon mode.
+use crate::SnapshotType;
+use crate::status::{Status, Triggers};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_util::sync::CancellationToken;
+
+/// Serve HTTP requests on `addr` until the token is cancelled
+/// - `GET /metrics`: Prometheus metrics
+/// - `GET /healthz`: 200 if all scheduler tasks are running, 503 otherwise
+/// - `GET /status`: JSON status of schedules, current operation and volumes
+/// - `POST /trigger/{full|incremental|cleanup}`: run a backup or a cleanup now, 409 if one is
+///   already pending, 503 if the task is stopped
+pub async fn serve(
+    addr: SocketAddr,
+    status: Arc<Status>,
+    triggers: Triggers,
+    cancel_token: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Listening on http://{addr}");
+
+    loop {
+        let (stream, _) = select! {
+            accepted = listener.accept() => accepted?,
+            _ = cancel_token.cancelled() => break,
+        };
+
+        let status = Arc::clone(&status);
+        let triggers = triggers.clone();
+        tokio::spawn(async move {
+            let service = service_fn(|request| handle(request, &status, &triggers));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                log::debug!("HTTP connection error: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    status: &Status,
+    triggers: &Triggers,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => response(
+            StatusCode::OK,
+            "text/plain; version=0.0.4",
+            crate::metrics::render(),
+        ),
+        (&Method::GET, "/healthz") => {
+            if status.healthy() {
+                response(StatusCode::OK, "text/plain", "ok\n".to_string())
+            } else {
+                response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "text/plain",
+                    "scheduler task stopped\n".to_string(),
+                )
+            }
+        }
+        (&Method::GET, "/status") => match serde_json::to_string_pretty(&status.report()) {
+            Ok(body) => response(StatusCode::OK, "application/json", body),
+            Err(e) => response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain",
+                format!("{e}\n"),
+            ),
+        },
+        (&Method::POST, "/trigger/full") => trigger(triggers.backup.try_send(SnapshotType::Full)),
+        (&Method::POST, "/trigger/incremental") => {
+            trigger(triggers.backup.try_send(SnapshotType::Incremental))
+        }
+        (&Method::POST, "/trigger/cleanup") => trigger(triggers.cleanup.try_send(())),
+        _ => response(
+            StatusCode::NOT_FOUND,
+            "text/plain",
+            "Not Found\n".to_string(),
+        ),
+    };
+    Ok(response)
+}
+
+/// Response to a trigger: accepted, already pending, or refused because the task is stopped
+fn trigger<T>(sent: Result<(), TrySendError<T>>) -> Response<Full<Bytes>> {
+    match sent {
+        Ok(()) => response(
+            StatusCode::ACCEPTED,
+            "text/plain",
+            "triggered\n".to_string(),
+        ),
+        Err(TrySendError::Full(_)) => response(
+            StatusCode::CONFLICT,
+            "text/plain",
+            "a run is already pending\n".to_string(),
+        ),
+        Err(TrySendError::Closed(_)) => response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "text/plain",
+            "scheduler task stopped\n".to_string(),
+        ),
+    }
+}
+
+fn response(status: StatusCode, content_type: &str, body: String) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    if let Ok(value) = content_type.parse() {
+        response
+            .headers_mut()
+            .insert(hyper::header::CONTENT_TYPE, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod test_http {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn trigger_status() {
+        let (sender, receiver) = mpsc::channel(1);
+        assert_eq!(trigger(sender.try_send(())).status(), StatusCode::ACCEPTED);
+        assert_eq!(trigger(sender.try_send(())).status(), StatusCode::CONFLICT);
+        // A stopped task does not receive the runs anymore
+        drop(receiver);
+        assert_eq!(
+            trigger(sender.try_send(())).status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
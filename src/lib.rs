@@ -1,13 +1,34 @@
 pub mod config;
+pub mod credentials;
+pub mod hooks;
+pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod notify;
+pub mod progress;
+pub mod retry;
 pub mod s3;
+pub mod schedule;
+pub mod state;
+pub mod status;
+pub mod throttle;
 pub mod zfs;
 
-use crate::s3::S3Client;
+use crate::config::Config;
+use crate::hooks::{HookContext, HookEvent};
+use crate::progress::Progress;
+use crate::s3::{S3Client, S3Error, UploadOptions};
+use crate::state::{StateStore, UploadRecord, UploadStatus};
+use crate::throttle::Throttle;
 use crate::zfs::{SUFFIX_SEPARATOR, Snapshot, VolumeSnapshotMap, ZfsError};
 use chrono::{DateTime, Utc};
 use clap::ValueEnum;
+use futures::stream::StreamExt;
 use std::collections::HashSet;
 use std::fmt::Display;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 
 // Backup conventions:
 // snapshot suffix: @auto-backup-2025-10-17T04:06:55Z
@@ -22,6 +43,7 @@ pub enum Zfs2S3Error {
     UploadError(String),
     SnapshotFailures(Vec<Box<dyn std::error::Error + Send + Sync>>),
     UploadFailures(Vec<Box<dyn std::error::Error + Send + Sync>>),
+    RestoreError(String),
 }
 
 impl Display for Zfs2S3Error {
@@ -43,6 +65,7 @@ impl Display for Zfs2S3Error {
                 }
                 Ok(())
             }
+            Zfs2S3Error::RestoreError(msg) => write!(f, "Restore Error: {}", msg),
         }
     }
 }
@@ -67,6 +90,7 @@ impl Display for SnapshotType {
 pub async fn snapshot_volumes(
     volumes: &VolumeSnapshotMap,
     snapshot_type: &SnapshotType,
+    config: &Config,
 ) -> Result<(), Zfs2S3Error> {
     let timestamp = format_iso_8601(&Utc::now());
     let mut errors: Vec<Box<dyn std::error::Error + Send + Sync>> = Vec::new();
@@ -76,9 +100,13 @@ pub async fn snapshot_volumes(
         SnapshotType::Incremental => BACKUP_SUFFIX_INCREMENTAL,
     };
 
-    for volume in volumes.volumes() {
-        let name = format!("{volume}{SUFFIX_SEPARATOR}{suffix}{timestamp}");
-        if let Err(e) = zfs::snapshot(&name).await {
+    // Volumes of a consistency group are snapshotted with a single command
+    for unit in volumes.snapshot_units() {
+        let names: Vec<String> = unit
+            .iter()
+            .map(|volume| format!("{volume}{SUFFIX_SEPARATOR}{suffix}{timestamp}"))
+            .collect();
+        if let Err(e) = snapshot_unit(volumes, &unit, &names, snapshot_type, config).await {
             errors.push(e);
         }
     }
@@ -89,21 +117,33 @@ pub async fn snapshot_volumes(
     Ok(())
 }
 
-pub async fn ensure_snapshots_for_volumes(volumes: &VolumeSnapshotMap) -> Result<(), Zfs2S3Error> {
+pub async fn ensure_snapshots_for_volumes(
+    volumes: &VolumeSnapshotMap,
+    config: &Config,
+) -> Result<(), Zfs2S3Error> {
     let mut errors: Vec<Box<dyn std::error::Error + Send + Sync>> = Vec::new();
 
-    for volume in &volumes.volumes {
-        // Check if there is at least one snapshot
-        let has_snapshot = volume.1.iter().any(|snapshot| {
-            snapshot.name.contains(BACKUP_SUFFIX)
-                && !snapshot.name.contains(BACKUP_SUFFIX_INCREMENTAL)
+    for unit in volumes.snapshot_units() {
+        // Check if there is at least one snapshot for every volume of the unit
+        let has_snapshot = unit.iter().all(|volume| {
+            volumes.volumes.get(volume).is_some_and(|snapshots| {
+                snapshots.iter().any(|snapshot| {
+                    snapshot.name.contains(BACKUP_SUFFIX)
+                        && !snapshot.name.contains(BACKUP_SUFFIX_INCREMENTAL)
+                })
+            })
         });
 
         if !has_snapshot {
-            // Create a full snapshot
+            // Create a full snapshot. A consistency group gets a new full snapshot
+            // for all its volumes so that they share the same chain.
             let timestamp = format_iso_8601(&Utc::now());
-            let name = format!("{}{SUFFIX_SEPARATOR}{BACKUP_SUFFIX}{timestamp}", volume.0);
-            if let Err(e) = zfs::snapshot(&name).await {
+            let names: Vec<String> = unit
+                .iter()
+                .map(|volume| format!("{volume}{SUFFIX_SEPARATOR}{BACKUP_SUFFIX}{timestamp}"))
+                .collect();
+            if let Err(e) = snapshot_unit(volumes, &unit, &names, &SnapshotType::Full, config).await
+            {
                 errors.push(e);
             }
         }
@@ -116,11 +156,107 @@ pub async fn ensure_snapshots_for_volumes(volumes: &VolumeSnapshotMap) -> Result
     Ok(())
 }
 
+/// Snapshot the volumes of a unit with a single command, running the configured hooks around it.
+/// Post-snapshot hooks always run once pre-snapshot hooks were started so that they can undo
+/// what was done before the snapshot, e.g. thaw a filesystem.
+async fn snapshot_unit(
+    volumes: &VolumeSnapshotMap,
+    unit: &[String],
+    names: &[String],
+    snapshot_type: &SnapshotType,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let hooks = &config.backup.hooks;
+    let mut ctx = HookContext {
+        volumes: unit,
+        snapshots: names,
+        snapshot_type,
+        group: unit.first().and_then(|v| volumes.group_of(v)),
+        error: None,
+    };
+
+    let result = match hooks::run_hooks(hooks, HookEvent::PreSnapshot, &ctx).await {
+        Ok(()) => zfs::snapshot_many(names).await,
+        Err(e) => Err(e),
+    };
+    let result = match hooks::run_hooks(hooks, HookEvent::PostSnapshot, &ctx).await {
+        Ok(()) => result,
+        Err(e) => result.and(Err(e)),
+    };
+
+    match &result {
+        Ok(()) => {
+            log::info!(snapshots:? = names; "Created {snapshot_type} snapshots");
+            metrics::snapshots_created(snapshot_type, names.len())
+        }
+        Err(e) => {
+            log::error!(snapshots:? = names, error:% = e; "Failed to create {snapshot_type} snapshots");
+            metrics::snapshot_failed()
+        }
+    }
+
+    if let Err(e) = &result {
+        let error = e.to_string();
+        ctx.error = Some(&error);
+        if let Err(e) = hooks::run_hooks(hooks, HookEvent::OnFailure, &ctx).await {
+            log::error!("{e}");
+        }
+    }
+    result
+}
+
+/// Shutdown of the daemon, as seen by uploads
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    /// Cancelled when a shutdown is requested or the upload window closes. Uploads in progress
+    /// continue but no new upload is started.
+    pub requested: CancellationToken,
+    /// Cancelled when the grace period is over. Uploads in progress are aborted.
+    pub abort: CancellationToken,
+}
+
+/// Outcome of the upload of a single snapshot
+#[derive(Debug, Clone)]
+pub struct UploadOutcome {
+    pub volume: String,
+    pub snapshot: String,
+    /// Bytes sent to S3
+    pub bytes: u64,
+    pub duration: Duration,
+    /// Error message if the upload failed
+    pub error: Option<String>,
+    /// The upload was aborted by a shutdown
+    pub interrupted: bool,
+}
+
+/// Summary of a sync between local snapshots and S3
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub uploads: Vec<UploadOutcome>,
+    /// Keys deleted from S3
+    pub deleted: Vec<String>,
+    /// Consistency groups for which at least one upload failed
+    pub incomplete_groups: Vec<String>,
+    /// Volumes whose snapshots could not be synced, with the error
+    pub volume_errors: Vec<(String, String)>,
+}
+
+impl SyncReport {
+    pub fn failed_uploads(&self) -> impl Iterator<Item = &UploadOutcome> {
+        self.uploads
+            .iter()
+            .filter(|u| u.error.is_some() && !u.interrupted)
+    }
+}
+
 /// Upload the latest snapshot of a single volume to S3
 async fn upload_single_full_snapshot_to_s3(
     s3: &S3Client,
     volume: (&str, &[Snapshot]),
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    send_flags: &[String],
+    options: &UploadOptions<'_>,
+    progress: &Arc<Progress>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
     // Verify the latest snapshot exists
     let latest_snapshot = if let Some(snapshot) = volume.1.first() {
         snapshot
@@ -145,18 +281,24 @@ async fn upload_single_full_snapshot_to_s3(
     let key = latest_snapshot.to_key()?;
 
     // Upload the snapshot to S3
-    let snapshot = zfs::stream_snapshot(&latest_snapshot.name).await?;
-    log::info!("Uploading snapshot {key}");
-    s3.upload_stream(snapshot, key).await?;
-
-    Ok(())
+    let size = estimate_send_size(None, &latest_snapshot.name, send_flags).await;
+    let snapshot = zfs::stream_snapshot(&latest_snapshot.name, send_flags).await?;
+    log::info!(volume = volume.0, snapshot = latest_snapshot.name, key; "Uploading snapshot {key}");
+    let bytes = s3
+        .upload_stream(snapshot, key, size, options, progress)
+        .await?;
+
+    Ok(bytes)
 }
 
 /// Upload the latest incremental snapshot of a single volume to S3
 async fn upload_single_incremental_snapshot_to_s3(
     s3: &S3Client,
     volume: (&str, &[Snapshot]),
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    send_flags: &[String],
+    options: &UploadOptions<'_>,
+    progress: &Arc<Progress>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
     // Grab the two newest snapshots
     let (to, from) = match volume.1.get(0..2) {
         Some(snapshots) if snapshots.len() == 2 => (&snapshots[0], &snapshots[1]),
@@ -182,69 +324,343 @@ async fn upload_single_incremental_snapshot_to_s3(
     let key = to.to_key()?;
 
     // Upload the snapshot to S3
-    let snapshot = zfs::stream_incremental_snapshot(&from.name, &to.name).await?;
-    log::info!("Uploading snapshot {key}");
-    s3.upload_stream(snapshot, key).await?;
+    let size = estimate_send_size(Some(&from.name), &to.name, send_flags).await;
+    let snapshot = zfs::stream_incremental_snapshot(&from.name, &to.name, send_flags).await?;
+    log::info!(volume = volume.0, snapshot = to.name, key; "Uploading snapshot {key}");
+    let bytes = s3
+        .upload_stream(snapshot, key, size, options, progress)
+        .await?;
+
+    Ok(bytes)
+}
 
-    Ok(())
+/// Estimated size of a send stream, none if it cannot be estimated
+async fn estimate_send_size(from: Option<&str>, to: &str, send_flags: &[String]) -> Option<u64> {
+    match zfs::estimate_send_size(from, to, send_flags).await {
+        Ok(size) => Some(size),
+        Err(e) => {
+            log::warn!("{e}, using the default part size");
+            None
+        }
+    }
 }
 
 /// Sync local snapshots to S3 by uploading missing snapshots and deleting removed snapshots
 pub async fn sync_snapshots(
     s3: &S3Client,
     volumes: &VolumeSnapshotMap,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    sync_missing_snapshots(s3, volumes).await?;
-    sync_deleted_snapshots(s3, volumes).await?;
-    Ok(())
+    config: &Config,
+    state: &StateStore,
+    shutdown: &Shutdown,
+) -> Result<SyncReport, Box<dyn std::error::Error + Send + Sync>> {
+    let mut report = SyncReport::default();
+
+    // A single listing is shared by both steps, and reused from the state if it is recent enough
+    let s3_objects = match state.s3_objects(config.state.listing_max_age()?) {
+        Some(keys) => keys,
+        None => {
+            let objects = s3.list_objects().await?;
+            metrics::s3_objects(objects.len() as u64, objects.iter().map(|o| o.size).sum());
+            let keys: HashSet<String> = objects.into_iter().map(|o| o.key).collect();
+            state.set_s3_objects(keys.clone());
+            keys
+        }
+    };
+
+    let ctx = SyncContext {
+        s3,
+        config,
+        state,
+        shutdown,
+        throttle: Throttle::new(&config.upload, config.schedule.timezone()?)?,
+        s3_objects: &s3_objects,
+    };
+    sync_missing_snapshots(&ctx, volumes, &mut report).await;
+    if !shutdown.requested.is_cancelled() {
+        sync_deleted_snapshots(&ctx, volumes, &mut report).await?;
+    }
+
+    // Forget about uploads of snapshots which no longer exist locally
+    let local_keys: HashSet<&str> = volumes
+        .volumes
+        .values()
+        .flat_map(|snapshots| snapshots.iter().filter_map(|s| s.to_key().ok()))
+        .collect();
+    state.retain_uploads(|r| local_keys.contains(r.key.as_str()));
+    if let Err(e) = state.save().await {
+        log::error!("Failed to save state: {e}");
+    }
+
+    Ok(report)
+}
+
+/// Resources shared by the uploads of a sync
+struct SyncContext<'a> {
+    s3: &'a S3Client,
+    config: &'a Config,
+    state: &'a StateStore,
+    shutdown: &'a Shutdown,
+    throttle: Throttle,
+    /// Keys of the objects in the bucket
+    s3_objects: &'a HashSet<String>,
 }
 
 /// Sync local snapshots to S3 by uploading missing snapshots
 async fn sync_missing_snapshots(
-    s3: &S3Client,
+    ctx: &SyncContext<'_>,
     volumes: &VolumeSnapshotMap,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let s3_objects = s3.list_objects().await?;
-
-    for volume in volumes.volumes.iter() {
-        // Reminder: snapshots are sorted from newest to oldest
-        for (i, snapshot) in volume.1.iter().enumerate() {
-            // Snapshot names in S3 are stored without the pool prefix
-            let key = snapshot.to_key()?;
-            if !s3_objects.contains(&key.to_string()) {
-                // Create a slice from the current snapshot onward
-                // This is because the upload functions only upload the latest snapshot (full or incremental)
-                let snapshots = volume.1[i..].as_ref();
-                let volume = (volume.0.as_str(), snapshots);
-
-                // Upload the snapshot
-                if is_incremental_snapshot(&snapshot.name) {
-                    if let Err(e) = upload_single_incremental_snapshot_to_s3(s3, volume).await {
-                        log::error!(
-                            "Failed to upload incremental snapshot {}: {}",
-                            snapshot.name,
-                            e
-                        );
+    report: &mut SyncReport,
+) {
+    // Volumes are uploaded in parallel, the snapshots of a volume one after the other. The
+    // error of a volume does not stop the others.
+    let mut uploads = Vec::new();
+    for (volume, snapshots) in volumes.volumes.iter() {
+        uploads.push(async move {
+            let outcomes = sync_missing_volume_snapshots(ctx, volumes, (volume, snapshots)).await;
+            (volume, outcomes)
+        });
+    }
+    let results: Vec<_> = futures::stream::iter(uploads)
+        .buffer_unordered(ctx.config.upload.max_parallel_volumes())
+        .collect()
+        .await;
+    for (volume, outcomes) in results {
+        match outcomes {
+            Ok(outcomes) => report.uploads.extend(outcomes),
+            Err(e) => {
+                log::error!(volume, error:% = e; "Failed to sync snapshots of {volume}: {e}");
+                report.volume_errors.push((volume.clone(), e.to_string()));
+            }
+        }
+    }
+
+    // Report consistency groups as a single unit
+    for (group, members) in volumes.groups.iter() {
+        let failed: Vec<&str> = report
+            .failed_uploads()
+            .map(|u| u.volume.as_str())
+            .chain(report.volume_errors.iter().map(|(v, _)| v.as_str()))
+            .filter(|v| members.iter().any(|m| m == v))
+            .collect();
+        if !failed.is_empty() {
+            log::error!(
+                "Consistency group {group} is incomplete on S3, failed volumes: {}",
+                failed.join(", ")
+            );
+            report.incomplete_groups.push(group.clone());
+        } else if report.uploads.iter().any(|u| members.contains(&u.volume)) {
+            log::info!("Consistency group {group} uploaded");
+        }
+    }
+}
+
+/// Upload the missing snapshots of a single volume, one after the other
+async fn sync_missing_volume_snapshots(
+    ctx: &SyncContext<'_>,
+    volumes: &VolumeSnapshotMap,
+    volume: (&str, &[Snapshot]),
+) -> Result<Vec<UploadOutcome>, Box<dyn std::error::Error + Send + Sync>> {
+    let SyncContext {
+        s3,
+        config,
+        state,
+        shutdown,
+        s3_objects,
+        ..
+    } = ctx;
+    let options = UploadOptions {
+        max_parallel_parts: config.upload.max_parallel_parts(),
+        limiters: ctx.throttle.limiters(volume.0),
+        abort: &shutdown.abort,
+    };
+    let send_flags = config.policy(volumes.policy_of(volume.0)).send_flags;
+    let mut outcomes = Vec::new();
+    // Reminder: snapshots are sorted from newest to oldest
+    let (volume, snapshots) = volume;
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        // Snapshot names in S3 are stored without the pool prefix
+        let key = snapshot.to_key()?;
+        if !s3_objects.contains(key) {
+            if shutdown.requested.is_cancelled() {
+                log::info!("Uploads stopped, remaining snapshots are uploaded by the next sync");
+                break;
+            }
+
+            // Create a slice from the current snapshot onward
+            // This is because the upload functions only upload the latest snapshot (full or incremental)
+            let snapshots = snapshots[i..].as_ref();
+            let volume = (volume, snapshots);
+
+            match state.upload(key) {
+                Some(previous) if previous.status == UploadStatus::Failed => log::info!(
+                    "Retrying upload of {key} after {} failed attempt(s), last error: {}",
+                    previous.attempts,
+                    previous.error.unwrap_or_default()
+                ),
+                Some(previous) if previous.status == UploadStatus::Interrupted => {
+                    log::info!("Resuming upload of {key} interrupted by a shutdown")
+                }
+                _ => {}
+            }
+
+            // Upload the snapshot
+            let started = Utc::now();
+            let start = Instant::now();
+            // A failed attempt sends the snapshot again from the start
+            let progress = progress::track(volume.0, key);
+            let result = retry::retry(
+                &config.retry,
+                &format!("Upload of {key}"),
+                &shutdown.requested,
+                || async {
+                    if is_incremental_snapshot(&snapshot.name) {
+                        upload_single_incremental_snapshot_to_s3(
+                            s3, volume, send_flags, &options, &progress,
+                        )
+                        .await
+                    } else {
+                        upload_single_full_snapshot_to_s3(
+                            s3, volume, send_flags, &options, &progress,
+                        )
+                        .await
                     }
-                } else if let Err(e) = upload_single_full_snapshot_to_s3(s3, volume).await {
-                    log::error!("Failed to upload full snapshot {}: {}", snapshot.name, e);
+                },
+            )
+            .await;
+            let duration = start.elapsed();
+            let snapshot_type = snapshot_type_of(&snapshot.name);
+            let interrupted = result
+                .as_ref()
+                .is_err_and(|e| e.downcast_ref::<S3Error>().is_some());
+            let duration_ms = duration.as_millis() as u64;
+            match &result {
+                Err(e) if interrupted => {
+                    log::warn!(volume = volume.0, snapshot = snapshot.name, key, duration_ms, error:% = e; "{e}")
                 }
+                Err(e) => log::error!(
+                    volume = volume.0, snapshot = snapshot.name, key, duration_ms, error:% = e;
+                    "Failed to upload {} snapshot {}: {}",
+                    snapshot_type,
+                    snapshot.name,
+                    e
+                ),
+                Ok(bytes) => log::info!(
+                    volume = volume.0, snapshot = snapshot.name, key, bytes, duration_ms;
+                    "Uploaded snapshot {key}"
+                ),
             }
+            // Bytes sent before a failure are kept to show how far the upload went
+            let (bytes, error) = match result {
+                Ok(bytes) => (bytes, None),
+                Err(e) => (progress.bytes_sent(), Some(e.to_string())),
+            };
+            drop(progress);
+
+            // Keep track of the upload and the chain lineage
+            let parent = match snapshot_type {
+                SnapshotType::Incremental => volume.1.get(1).and_then(|s| s.to_key().ok()),
+                SnapshotType::Full => None,
+            };
+            state.record_upload(UploadRecord {
+                volume: volume.0.to_string(),
+                snapshot: snapshot.name.clone(),
+                key: key.to_string(),
+                parent: parent.map(|p| p.to_string()),
+                started,
+                duration_ms,
+                bytes,
+                attempts: 1,
+                status: match error {
+                    None => UploadStatus::Succeeded,
+                    Some(_) if interrupted => UploadStatus::Interrupted,
+                    Some(_) => UploadStatus::Failed,
+                },
+                error: error.clone(),
+            });
+            match error {
+                None => {
+                    state.update_s3_object(key, true);
+                    metrics::upload_succeeded(
+                        volume.0,
+                        &snapshot_type,
+                        bytes,
+                        duration,
+                        Utc::now(),
+                    );
+                }
+                Some(_) if interrupted => {}
+                Some(_) => metrics::upload_failed(volume.0),
+            }
+
+            if interrupted {
+                outcomes.push(UploadOutcome {
+                    volume: volume.0.to_string(),
+                    snapshot: snapshot.name.clone(),
+                    bytes,
+                    duration,
+                    error,
+                    interrupted,
+                });
+                break;
+            }
+
+            // Run post-upload or on-failure hooks
+            let volume_names = [volume.0.to_string()];
+            let snapshot_names = [snapshot.name.clone()];
+            let ctx = HookContext {
+                volumes: &volume_names,
+                snapshots: &snapshot_names,
+                snapshot_type: &snapshot_type,
+                group: volumes.group_of(volume.0),
+                error: error.as_deref(),
+            };
+            let event = match error {
+                None => HookEvent::PostUpload,
+                Some(_) => HookEvent::OnFailure,
+            };
+            if let Err(e) = hooks::run_hooks(&config.backup.hooks, event, &ctx).await {
+                log::error!("{e}");
+            }
+
+            outcomes.push(UploadOutcome {
+                volume: volume.0.to_string(),
+                snapshot: snapshot.name.clone(),
+                bytes,
+                duration,
+                error,
+                interrupted,
+            });
         }
     }
 
-    Ok(())
+    Ok(outcomes)
 }
 
 /// Sync deleted snapshots from S3 by removing snapshots that no longer exist locally
 /// This function checks for each snapshot in S3 if it exists in the local volumes.
 /// If a snapshot is missing locally, it deletes that snapshot from S3.
 async fn sync_deleted_snapshots(
-    s3: &S3Client,
+    ctx: &SyncContext<'_>,
     volumes: &VolumeSnapshotMap,
+    report: &mut SyncReport,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let s3_objects = s3.list_objects().await?;
-
+    let SyncContext {
+        s3,
+        config,
+        state,
+        shutdown,
+        s3_objects,
+        ..
+    } = ctx;
+    // Keys start with the name of the volume without its pool
+    let excluded: HashSet<&str> = match config.backup.delete_excluded {
+        true => HashSet::new(),
+        false => volumes
+            .excluded
+            .iter()
+            .filter_map(|volume| volume.split('/').next_back())
+            .collect(),
+    };
     let local_snapshot_names: HashSet<&str> = volumes
         .volumes
         .iter()
@@ -259,9 +675,27 @@ async fn sync_deleted_snapshots(
     for object in s3_objects.iter() {
         // Snapshot names in S3 are stored without the pool prefix
         if !local_snapshot_names.iter().any(|s| s.eq(object)) {
-            log::info!("Deleting {object} from S3.");
-            if let Err(e) = s3.delete_object(object).await {
-                log::error!("Failed to delete snapshot {object} from S3: {}", e);
+            if shutdown.requested.is_cancelled() {
+                break;
+            }
+            if let Some((volume, _)) = object.split_once('@')
+                && excluded.contains(volume)
+            {
+                log::debug!("Keeping {object} of excluded volume {volume} on S3");
+                continue;
+            }
+            log::info!(key = object; "Deleting {object} from S3.");
+            match s3.delete_object(object).await {
+                Ok(()) => {
+                    metrics::retention_deleted("s3");
+                    state.update_s3_object(object, false);
+                    report.deleted.push(object.clone());
+                }
+                Err(e) => log::error!(
+                    key = object, error:% = e;
+                    "Failed to delete snapshot {object} from S3: {}",
+                    e
+                ),
             }
         }
     }
@@ -269,10 +703,125 @@ async fn sync_deleted_snapshots(
     Ok(())
 }
 
+/// Restore the snapshot stored under `key` into `dataset`. The full snapshot and the
+/// incremental snapshots `key` depends on are received first, oldest first.
+pub async fn restore_snapshot(
+    s3: &S3Client,
+    key: &str,
+    dataset: &str,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let objects = s3.list_objects().await?;
+    let chain = restore_chain(objects.iter().map(|o| o.key.as_str()), key)?;
+
+    for (i, key) in chain.iter().enumerate() {
+        log::info!(key, dataset; "Receiving {key} into {dataset} ({}/{})", i + 1, chain.len());
+        let start = Instant::now();
+        let stream = s3.download_stream(key).await?;
+        // Only the first stream may need to roll back the dataset
+        let bytes = zfs::receive(dataset, force && i == 0, stream).await?;
+        let duration_ms = start.elapsed().as_millis() as u64;
+        log::info!(key, dataset, bytes, duration_ms; "Received {key} into {dataset}");
+    }
+
+    Ok(())
+}
+
+/// Timestamp and type of a snapshot from its name or key, none if it was not created by a backup
+fn parse_backup_suffix(name: &str) -> Option<(DateTime<Utc>, SnapshotType)> {
+    let (_, suffix) = name.split_once(SUFFIX_SEPARATOR)?;
+    // The incremental suffix starts with the full suffix, check it first
+    let (timestamp, snapshot_type) = match suffix.strip_prefix(BACKUP_SUFFIX_INCREMENTAL) {
+        Some(timestamp) => (timestamp, SnapshotType::Incremental),
+        None => (suffix.strip_prefix(BACKUP_SUFFIX)?, SnapshotType::Full),
+    };
+    let timestamp = chrono::NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+    Some((timestamp.and_utc(), snapshot_type))
+}
+
+/// Keys to receive, in order, to restore `key`: the latest full snapshot of the volume up to
+/// `key`, then the incremental snapshots following it
+fn restore_chain<'a>(
+    keys: impl IntoIterator<Item = &'a str>,
+    key: &str,
+) -> Result<Vec<String>, Zfs2S3Error> {
+    let (volume, _) = key
+        .split_once(SUFFIX_SEPARATOR)
+        .ok_or_else(|| Zfs2S3Error::RestoreError(format!("Invalid snapshot key: {key}")))?;
+    let (timestamp, _) = parse_backup_suffix(key)
+        .ok_or_else(|| Zfs2S3Error::RestoreError(format!("{key} was not created by a backup")))?;
+
+    let mut candidates: Vec<(DateTime<Utc>, SnapshotType, &str)> = keys
+        .into_iter()
+        .filter(|k| {
+            k.split_once(SUFFIX_SEPARATOR)
+                .is_some_and(|(v, _)| v == volume)
+        })
+        .filter_map(|k| parse_backup_suffix(k).map(|(t, snapshot_type)| (t, snapshot_type, k)))
+        .filter(|(t, _, _)| *t <= timestamp)
+        .collect();
+    candidates.sort_by_key(|(t, _, _)| *t);
+
+    if candidates.last().is_none_or(|(_, _, k)| *k != key) {
+        return Err(Zfs2S3Error::RestoreError(format!("{key} not found on S3")));
+    }
+    let start = candidates
+        .iter()
+        .rposition(|(_, snapshot_type, _)| *snapshot_type == SnapshotType::Full)
+        .ok_or_else(|| {
+            Zfs2S3Error::RestoreError(format!("No full snapshot of {volume} found on S3"))
+        })?;
+
+    Ok(candidates[start..]
+        .iter()
+        .map(|(_, _, k)| k.to_string())
+        .collect())
+}
+
 fn is_incremental_snapshot(snapshot_name: &str) -> bool {
     snapshot_name.contains(BACKUP_SUFFIX_INCREMENTAL)
 }
 
+pub fn snapshot_type_of(snapshot_name: &str) -> SnapshotType {
+    if is_incremental_snapshot(snapshot_name) {
+        SnapshotType::Incremental
+    } else {
+        SnapshotType::Full
+    }
+}
+
+/// Name of the host, used to identify the source of notifications
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
 fn format_iso_8601(t: &DateTime<Utc>) -> String {
     t.format(TIMESTAMP_FORMAT).to_string()
 }
+
+#[cfg(test)]
+mod test_restore {
+    use super::*;
+
+    #[test]
+    fn chain_from_latest_full() {
+        let keys = [
+            "vm-100-disk-0@auto-backup-2024-05-01T05:00:00Z",
+            "vm-100-disk-0@auto-backup-incremental-2024-05-02T04:30:00Z",
+            "vm-100-disk-0@auto-backup-2024-05-08T05:00:00Z",
+            "vm-100-disk-0@auto-backup-incremental-2024-05-09T04:30:00Z",
+            "vm-100-disk-0@auto-backup-incremental-2024-05-10T04:30:00Z",
+            "vm-100-disk-0@auto-backup-incremental-2024-05-11T04:30:00Z",
+            "vm-101-disk-0@auto-backup-incremental-2024-05-09T05:00:00Z",
+        ];
+        assert_eq!(
+            restore_chain(keys, keys[4]).unwrap(),
+            vec![keys[2], keys[3], keys[4]]
+        );
+        assert_eq!(restore_chain(keys, keys[0]).unwrap(), vec![keys[0]]);
+        assert!(restore_chain(keys, keys[6]).is_err());
+        assert!(restore_chain(keys, "vm-100-disk-0@auto-backup-2024-06-01T05:00:00Z").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+/// Logger setup with text or JSON output, and the context of the runs added to their events.
+use chrono::{SecondsFormat, Utc};
+use clap::ValueEnum;
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::Map;
+use std::fmt::Display;
+use std::future::Future;
+use std::io::Write;
+
+/// Output format of the logs
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines, with the fields of the events appended
+    #[default]
+    Text,
+    /// One JSON object per event
+    Json,
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Run of an operation, e.g. a scheduled backup
+#[derive(Debug, Clone)]
+struct Run {
+    id: String,
+    operation: String,
+}
+
+tokio::task_local! {
+    static RUN: Run;
+}
+
+/// Run `future` as a new run of `operation`. The events it logs carry the operation and an id
+/// shared by the snapshots, uploads and deletions of the run.
+pub async fn run<F: Future>(operation: &str, future: F) -> F::Output {
+    let run = Run {
+        id: format!("{:08x}", rand::random::<u32>()),
+        operation: operation.to_string(),
+    };
+    RUN.scope(run, future).await
+}
+
+/// Id of the current run, none outside of a run
+pub fn run_id() -> Option<String> {
+    RUN.try_with(|run| run.id.clone()).ok()
+}
+
+/// Initialize the logger. The level is set with `RUST_LOG`.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_event(record)));
+    }
+    builder.init();
+}
+
+/// JSON object of an event, with its fields and the context of the current run
+fn json_event(record: &log::Record) -> serde_json::Value {
+    let mut event = Map::new();
+    event.insert(
+        "timestamp".to_string(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    event.insert(
+        "level".to_string(),
+        record.level().as_str().to_lowercase().into(),
+    );
+    event.insert("target".to_string(), record.target().into());
+    event.insert("message".to_string(), record.args().to_string().into());
+    if let Ok(run) = RUN.try_with(Run::clone) {
+        event.insert("run_id".to_string(), run.id.into());
+        event.insert("operation".to_string(), run.operation.into());
+    }
+    // A field can only fail to be visited if the visitor fails, which it never does
+    let _ = record.key_values().visit(&mut Fields(&mut event));
+    event.into()
+}
+
+/// Collect the fields of an event into a JSON object
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| value.to_string().into());
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_logging {
+    use super::*;
+
+    #[tokio::test]
+    async fn json_event_has_fields_and_run() {
+        let key = "vm-100-disk-0@test";
+        let fields: &[(&str, Value)] = &[("key", Value::from(key)), ("bytes", Value::from(42u64))];
+        let args = format_args!("Uploaded snapshot {key}");
+        let record = log::Record::builder()
+            .level(log::Level::Info)
+            .target("zfs2s3")
+            .args(args)
+            .key_values(&fields)
+            .build();
+
+        let event = json_event(&record);
+        assert_eq!(event["message"], "Uploaded snapshot vm-100-disk-0@test");
+        assert_eq!(event["level"], "info");
+        assert_eq!(event["key"], key);
+        assert_eq!(event["bytes"], 42);
+        assert!(event.get("run_id").is_none());
+
+        let (event, id) = run("cleanup", async { (json_event(&record), run_id()) }).await;
+        assert_eq!(event["operation"], "cleanup");
+        assert_eq!(event["run_id"], id.unwrap());
+    }
+}
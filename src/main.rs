@@ -1,115 +1,274 @@
 use chrono::Utc;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::path::Path;
+use std::process::ExitCode;
 use std::sync::Arc;
-use tokio::fs::read_to_string;
+use std::time::Duration;
 use tokio::select;
 use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{Notify, mpsc, watch};
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
-use tokio_util::sync::CancellationToken;
-use zfs2s3::config::Config;
-use zfs2s3::{SnapshotType, ensure_snapshots_for_volumes};
+use zfs2s3::config::{Config, DEFAULT_POLICY};
+use zfs2s3::credentials::Credentials;
+use zfs2s3::logging::LogFormat;
+use zfs2s3::notify::{NotificationEvent, Notifier};
+use zfs2s3::s3::S3Client;
+use zfs2s3::state::{StateStore, UploadStatus};
+use zfs2s3::status::{Status, Triggers};
+use zfs2s3::zfs::VolumeSnapshotMap;
+use zfs2s3::{Shutdown, SnapshotType, ensure_snapshots_for_volumes};
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(name = env!("CARGO_PKG_NAME"))]
 #[command(version = concat!("v", env!("CARGO_PKG_VERSION"), "+", env!("GIT_SHA")))]
 struct Args {
-    /// Run a single-shot backup (Full or Incremental)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Run a single-shot backup (Full or Incremental), same as the `backup` command
     #[arg(long)]
     single_shot: Option<SnapshotType>,
 
+    /// Show the progress of the uploads of a backup, sync or cleanup
+    #[arg(long, global = true)]
+    progress: bool,
+
+    /// Format of the logs
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+
     /// Configuration file path
-    #[arg(long, short = 'c', default_value = "config.toml")]
+    #[arg(long, short = 'c', default_value = "config.toml", global = true)]
     config: String,
 
-    /// S3 key ID
-    #[arg(long, env = "S3_ACCESS_KEY_ID")]
-    s3_key_id: String,
+    /// S3 key ID. Prefer the credential settings of `[s3]`, arguments and environment
+    /// variables are visible to other processes.
+    #[arg(long, env = "S3_ACCESS_KEY_ID", global = true)]
+    s3_key_id: Option<String>,
 
     /// S3 secret key
-    #[arg(long, env = "S3_SECRET_ACCESS_KEY")]
-    s3_secret_key: String,
+    #[arg(
+        long,
+        env = "S3_SECRET_ACCESS_KEY",
+        hide_env_values = true,
+        global = true
+    )]
+    s3_secret_key: Option<String>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    env_logger::init();
+#[derive(Subcommand, Clone, Debug, PartialEq, Eq)]
+enum Command {
+    /// Run the scheduled backups and cleanups (default)
+    Daemon,
+    /// Snapshot the volumes and sync them to S3
+    Backup {
+        #[arg(value_enum)]
+        snapshot_type: SnapshotType,
+        /// Only snapshot the volumes of this backup policy, `default` for the volumes without a
+        /// `[[backup.policy]]`
+        #[arg(long)]
+        policy: Option<String>,
+    },
+    /// Upload the missing snapshots and delete the removed ones from S3, without taking snapshots
+    Sync,
+    /// Apply the retention policy and delete the removed snapshots from S3
+    Cleanup,
+    /// List the snapshots of the volumes to back up and whether they are on S3
+    List,
+    /// Restore a snapshot from S3 into a dataset. An incremental snapshot is restored with the
+    /// full snapshot and the incremental snapshots it depends on.
+    Restore {
+        /// Key of the snapshot in the bucket, e.g. `vm-100-disk-0@auto-backup-2024-05-01T05:00:00Z`
+        key: String,
+        /// Dataset to receive the snapshots into
+        dataset: String,
+        /// Roll back the dataset to its most recent snapshot before receiving (`zfs receive -F`)
+        #[arg(long)]
+        force: bool,
+    },
+    /// Validate the configuration file, show the datasets matched by the volume patterns and
+    /// the next runs of the schedules
+    CheckConfig {
+        /// Number of upcoming times to show for each schedule
+        #[arg(long, default_value_t = 5)]
+        next: usize,
+        /// Check the S3 credentials by writing, reading and deleting a probe object
+        #[arg(long)]
+        probe_s3: bool,
+    },
+}
 
+#[tokio::main]
+async fn main() -> ExitCode {
     // Application arguments
     let args = Args::parse();
+    zfs2s3::logging::init(args.log_format);
+
+    // Errors are printed with their message, which for configuration errors includes the
+    // location in the file
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let command = match (&args.command, &args.single_shot) {
+        (Some(_), Some(_)) => return Err("--single-shot cannot be used with a command".into()),
+        (Some(command), None) => command.clone(),
+        (None, Some(snapshot_type)) => Command::Backup {
+            snapshot_type: snapshot_type.clone(),
+            policy: None,
+        },
+        (None, None) => Command::Daemon,
+    };
+    if args.progress
+        && !matches!(
+            command,
+            Command::Backup { .. } | Command::Sync | Command::Cleanup
+        )
+    {
+        return Err("--progress only applies to the backup, sync and cleanup commands".into());
+    }
 
     // Load configuration
-    let file = read_to_string(args.config).await?;
-    let config = Config::try_from(&file)?;
+    let config = Config::load(Path::new(&args.config)).await?;
 
-    // Get S3 client
-    let s3_client = zfs2s3::s3::S3Client::new(
-        &config.s3.url,
-        &config.s3.region,
-        &config.s3.bucket,
-        &args.s3_key_id,
-        &args.s3_secret_key,
-    )?;
+    if let Command::CheckConfig { next, probe_s3 } = command {
+        return check_config(&args, &config, next, probe_s3).await;
+    }
 
-    // single-shot mode?
-    if let Some(mode) = args.single_shot {
-        // Get volumes and their snapshots to back up
-        let mut volumes_to_backup = zfs2s3::zfs::VolumeSnapshotMap::new()
-            .await?
-            .keep_volume_to_backup(&config);
+    // Get S3 client
+    let s3_client = s3_client(&args, &config)?;
 
-        if mode == SnapshotType::Incremental {
-            ensure_snapshots_for_volumes(&volumes_to_backup).await?;
-        }
+    match command {
+        Command::List => return list(&s3_client, &config).await,
+        Command::Restore {
+            key,
+            dataset,
+            force,
+        } => return zfs2s3::restore_snapshot(&s3_client, &key, &dataset, force).await,
+        _ => {}
+    }
 
-        zfs2s3::snapshot_volumes(&volumes_to_backup, &mode).await?;
-        volumes_to_backup.refresh().await?;
+    // Load persisted state
+    let state = match &config.state.dir {
+        Some(dir) => StateStore::open(dir).await?,
+        None => StateStore::in_memory(),
+    };
 
-        if let Err(e) = zfs2s3::sync_snapshots(&s3_client, &volumes_to_backup).await {
-            log::error!("Failed to sync snapshots to S3: {e}");
+    // Restore the time of the last successful uploads for monitoring
+    for record in state.uploads() {
+        match record.status {
+            UploadStatus::Succeeded => {
+                let snapshot_type = zfs2s3::snapshot_type_of(&record.snapshot);
+                zfs2s3::metrics::last_success(&record.volume, &snapshot_type, record.finished());
+            }
+            UploadStatus::Interrupted => log::info!(
+                "Upload of {} was interrupted by the last shutdown, it is resumed by the next sync",
+                record.key
+            ),
+            UploadStatus::Failed => {}
         }
+    }
 
-        return Ok(());
+    let notifier = Notifier::new(&config.notify)?;
+
+    if command != Command::Daemon {
+        let single_run = SingleRun {
+            config: &config,
+            s3_client: &s3_client,
+            state: &state,
+            notifier: &notifier,
+            progress: args.progress,
+        };
+        return single_run.run(&command).await;
     }
 
     // Schedules
-    let config = Arc::new(config);
-    let s3_client = Arc::new(s3_client);
-    let cancel_token = CancellationToken::new();
+    let state = Arc::new(state);
+    let daemon = Arc::new(Daemon {
+        config: watch::Sender::new(Arc::new(config)),
+        s3_client: watch::Sender::new(Arc::new(s3_client)),
+        status: Arc::new(Status::new(Arc::clone(&state))),
+        state,
+        notifier,
+        shutdown: Shutdown::default(),
+        op_lock: tokio::sync::Mutex::new(()),
+        sync_lock: tokio::sync::Mutex::new(()),
+        pending_uploads: Notify::new(),
+    });
     let mut handles: Vec<JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>> =
         Vec::new();
 
     // Spawn signal handler
-    let signal_cancel_token = cancel_token.clone();
+    let signal_daemon = Arc::clone(&daemon);
+    let signal_args = args.clone();
     tokio::spawn(async move {
-        shutdown_signal().await;
-        signal_cancel_token.cancel();
+        shutdown_signal(&signal_daemon, &signal_args).await;
+        signal_daemon.shutdown.requested.cancel();
+
+        // Let uploads in progress finish, unless the grace period is over or the signal is sent again
+        let grace_period = signal_daemon
+            .config
+            .borrow()
+            .shutdown
+            .grace_period()
+            .unwrap_or_default();
+        log::info!(
+            "Shutting down, waiting up to {} for uploads in progress",
+            humantime::format_duration(grace_period)
+        );
+        select! {
+            _ = sleep(grace_period) => log::warn!("Shutdown grace period is over, aborting uploads"),
+            _ = shutdown_signal(&signal_daemon, &signal_args) => log::warn!("Aborting uploads"),
+        }
+        signal_daemon.shutdown.abort.cancel();
     });
 
-    // Operation lock to prevent concurrent backups and cleanups
-    let op_lock = Arc::new(tokio::sync::Mutex::new(()));
+    // Channels to trigger runs from the HTTP API
+    let (backup_trigger, backup_trigger_rx) = mpsc::channel(1);
+    let (cleanup_trigger, cleanup_trigger_rx) = mpsc::channel(1);
+
+    // HTTP server for metrics, health and status
+    let http_listen = daemon.config.borrow().http.listen;
+    if let Some(addr) = http_listen {
+        let triggers = Triggers {
+            backup: backup_trigger,
+            cleanup: cleanup_trigger,
+        };
+        handles.push(tokio::task::spawn(zfs2s3::http::serve(
+            addr,
+            Arc::clone(&daemon.status),
+            triggers,
+            daemon.shutdown.requested.clone(),
+        )));
+    }
 
     // Backup task
     let handle_full_backups = tokio::task::spawn(run_scheduled_backups(
-        Arc::clone(&config),
-        Arc::clone(&s3_client),
-        cancel_token.clone(),
-        Arc::clone(&op_lock),
+        Arc::clone(&daemon),
+        backup_trigger_rx,
     ));
     handles.push(handle_full_backups);
 
+    // Upload the snapshots independently of their creation
+    handles.push(tokio::task::spawn(run_uploads(Arc::clone(&daemon))));
+
     // Perform scheduled cleanup
-    let handle_cleanup = tokio::task::spawn({
-        run_cleanup(
-            Arc::clone(&config),
-            Arc::clone(&s3_client),
-            cancel_token.clone(),
-            Arc::clone(&op_lock),
-        )
-    });
+    let handle_cleanup = tokio::task::spawn(run_cleanup(Arc::clone(&daemon), cleanup_trigger_rx));
     handles.push(handle_cleanup);
 
+    // Periodic summary of the activity
+    handles.push(tokio::task::spawn(run_summary(Arc::clone(&daemon))));
+
     // Wait for all handles to complete
     for handle in handles {
         handle.await??;
@@ -118,140 +277,856 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     Ok(())
 }
 
-async fn shutdown_signal() {
+/// S3 client with the credentials of the arguments, or the ones configured in `[s3]`
+fn s3_client(
+    args: &Args,
+    config: &Config,
+) -> Result<S3Client, Box<dyn std::error::Error + Send + Sync>> {
+    let credentials = Credentials::resolve(
+        args.s3_key_id.as_deref(),
+        args.s3_secret_key.as_deref(),
+        &config.s3,
+    )?;
+    log::info!("Using S3 credentials from {credentials}");
+    S3Client::new(
+        &config.s3.url,
+        &config.s3.region,
+        &config.s3.bucket,
+        &credentials,
+        &config.retry,
+    )
+}
+
+/// Validate the configuration file and show what it applies to. Fails if a volume pattern
+/// matches no dataset or the S3 probe fails.
+async fn check_config(
+    args: &Args,
+    config: &Config,
+    next: usize,
+    probe_s3: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut problems = 0;
+    println!(
+        "Configuration {} is valid (version {})",
+        args.config,
+        config.version()
+    );
+    if !config.includes().is_empty() {
+        println!("Includes: {}", config.includes().join(", "));
+    }
+
+    println!("\nVolumes:");
+    let patterns = config
+        .backup
+        .volumes
+        .iter()
+        .map(|pattern| (pattern, String::new()))
+        .chain(config.backup.groups.iter().flat_map(|group| {
+            group
+                .volumes
+                .iter()
+                .map(|pattern| (pattern, format!(" (group {})", group.name)))
+        }))
+        .chain(config.backup.policies.iter().flat_map(|policy| {
+            policy
+                .volumes
+                .iter()
+                .map(|pattern| (pattern, format!(" (policy {})", policy.name)))
+        }))
+        .chain(
+            config
+                .backup
+                .exclude
+                .iter()
+                .map(|pattern| (pattern, " (exclude)".to_string())),
+        );
+    match zfs2s3::zfs::list_volume_properties().await {
+        Ok(listed) => {
+            let datasets: Vec<&String> = listed.iter().map(|(dataset, _)| dataset).collect();
+            for (pattern, label) in patterns {
+                let matches: Vec<&str> = datasets
+                    .iter()
+                    .filter(|d| fast_glob::glob_match(pattern, d.as_str()))
+                    .map(|d| d.as_str())
+                    .collect();
+                if matches.is_empty() {
+                    problems += 1;
+                    println!("  {pattern}{label}: no dataset matches");
+                } else {
+                    println!("  {pattern}{label}: {}", matches.join(", "));
+                }
+            }
+            for (dataset, property) in listed.iter() {
+                if let Some(property) = property {
+                    println!("  {dataset}: {}={property}", zfs2s3::zfs::BACKUP_PROPERTY);
+                }
+            }
+        }
+        Err(e) => {
+            problems += 1;
+            println!("  Failed to list the datasets: {e}");
+        }
+    }
+
+    let timezone = config.schedule.timezone()?;
+    println!("\nSchedules ({timezone}):");
+    for (key, schedule) in config.schedules()? {
+        println!("  {key} = \"{}\"", schedule.source());
+        let mut time = Utc::now();
+        for _ in 0..next {
+            let Some(next) = zfs2s3::schedule::next_run(&schedule, &timezone, time) else {
+                break;
+            };
+            println!("    {}", next.with_timezone(&timezone).to_rfc3339());
+            time = next;
+        }
+    }
+
+    if probe_s3 {
+        match s3_client(args, config)?.probe().await {
+            Ok(key) => println!(
+                "\nS3: wrote, read and deleted {key} in bucket {}",
+                config.s3.bucket
+            ),
+            Err(e) => {
+                problems += 1;
+                println!("\nS3: probe of bucket {} failed: {e}", config.s3.bucket);
+            }
+        }
+    }
+
+    if problems > 0 {
+        return Err(format!("{problems} problem(s) found in {}", args.config).into());
+    }
+    Ok(())
+}
+
+/// How often the upload task checks whether the upload window is still open
+const WINDOW_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Resources shared by the scheduled tasks
+struct Daemon {
+    /// Current configuration, replaced when the configuration is reloaded.
+    /// Tasks use the new configuration from their next iteration.
+    config: watch::Sender<Arc<Config>>,
+    /// S3 client, rebuilt when the configuration is reloaded. Uploads in progress keep the
+    /// client they started with.
+    s3_client: watch::Sender<Arc<S3Client>>,
+    state: Arc<StateStore>,
+    status: Arc<Status>,
+    notifier: Notifier,
+    shutdown: Shutdown,
+    /// Operation lock to prevent concurrent backups and cleanups
+    op_lock: tokio::sync::Mutex<()>,
+    /// Held while uploading, so that the cleanup does not destroy a snapshot being sent
+    sync_lock: tokio::sync::Mutex<()>,
+    /// Wakes up the upload task when snapshots are taken or destroyed
+    pending_uploads: Notify,
+}
+
+/// Resources of a single backup, sync or cleanup
+struct SingleRun<'a> {
+    config: &'a Config,
+    s3_client: &'a S3Client,
+    state: &'a StateStore,
+    notifier: &'a Notifier,
+    /// Show progress bars for the uploads
+    progress: bool,
+}
+
+impl SingleRun<'_> {
+    /// Run a backup, sync or cleanup once. Uploads are aborted on SIGTERM or SIGINT.
+    async fn run(&self, command: &Command) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let operation = match command {
+            Command::Backup { snapshot_type, .. } => format!("{snapshot_type}_backup"),
+            Command::Sync => "sync".to_string(),
+            _ => "cleanup".to_string(),
+        };
+
+        zfs2s3::logging::run(&operation, async {
+            // Get volumes and their snapshots to back up
+            let mut volumes = VolumeSnapshotMap::new()
+                .await?
+                .keep_volume_to_backup(self.config);
+
+            match command {
+                Command::Backup {
+                    snapshot_type,
+                    policy,
+                } => {
+                    if let Some(policy) = policy
+                        && !self.config.policies().iter().any(|p| p.name == policy)
+                    {
+                        return Err(format!("Unknown backup policy {policy}").into());
+                    }
+                    // Only the volumes of the policy are snapshotted, all of them are synced
+                    let targets = policy.as_deref().map(|p| volumes.with_policy(p));
+                    let targets = targets.as_ref().unwrap_or(&volumes);
+
+                    if *snapshot_type == SnapshotType::Incremental
+                        && let Err(e) = ensure_snapshots_for_volumes(targets, self.config).await
+                    {
+                        self.notifier.snapshot_failure(&e.to_string()).await;
+                        return Err(e.into());
+                    }
+
+                    if let Err(e) =
+                        zfs2s3::snapshot_volumes(targets, snapshot_type, self.config).await
+                    {
+                        self.notifier.snapshot_failure(&e.to_string()).await;
+                        return Err(e.into());
+                    }
+                    volumes.refresh().await?;
+                }
+                Command::Cleanup => {
+                    let deleted = volumes.apply_retention_policy(self.config).await?;
+                    self.notifier.retention(&deleted).await;
+                }
+                _ => {}
+            }
+
+            // Stop the sync and abort the upload in progress on SIGTERM or SIGINT, and exit
+            // right away on a second signal
+            let shutdown = Shutdown::default();
+            let requested = shutdown.requested.clone();
+            let abort = shutdown.abort.clone();
+            tokio::spawn(async move {
+                terminate_signal().await;
+                log::warn!("Aborting uploads");
+                requested.cancel();
+                abort.cancel();
+                terminate_signal().await;
+                log::warn!("Exiting without waiting for the uploads to be aborted");
+                std::process::exit(130);
+            });
+
+            let progress_bars = self.progress.then(|| tokio::spawn(show_progress()));
+            let result = zfs2s3::sync_snapshots(
+                self.s3_client,
+                &volumes,
+                self.config,
+                self.state,
+                &shutdown,
+            )
+            .await;
+            if let Some(progress_bars) = progress_bars {
+                progress_bars.abort();
+            }
+
+            match result {
+                Ok(report) => self.notifier.sync_report(&report).await,
+                Err(e) => {
+                    log::error!("Failed to sync snapshots to S3: {e}");
+                    self.notifier
+                        .notify(NotificationEvent::UploadFailure, &e.to_string())
+                        .await;
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Print the snapshots of the volumes to back up with their object on S3, then the objects
+/// without a local snapshot
+async fn list(
+    s3_client: &S3Client,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let volumes = VolumeSnapshotMap::new()
+        .await?
+        .keep_volume_to_backup(config);
+    let objects = s3_client.list_objects().await?;
+    let sizes: HashMap<&str, u64> = objects.iter().map(|o| (o.key.as_str(), o.size)).collect();
+
+    let mut names: Vec<&String> = volumes.volumes.keys().collect();
+    names.sort();
+    let mut local_keys = HashSet::new();
+    for volume in names {
+        println!("{volume}");
+        // Oldest snapshot first
+        for snapshot in volumes.volumes[volume].iter().rev() {
+            let key = snapshot.to_key()?;
+            local_keys.insert(key);
+            let on_s3 = match sizes.get(key) {
+                Some(size) => format!("on S3, {}", zfs2s3::progress::format_bytes(*size)),
+                None => "not on S3".to_string(),
+            };
+            println!(
+                "  {}  {}  {on_s3}",
+                snapshot.suffix().unwrap_or(key),
+                zfs2s3::snapshot_type_of(&snapshot.name)
+            );
+        }
+    }
+
+    let mut remote: Vec<_> = objects
+        .iter()
+        .filter(|o| !local_keys.contains(o.key.as_str()))
+        .collect();
+    if !remote.is_empty() {
+        remote.sort_by(|a, b| a.key.cmp(&b.key));
+        println!("Only on S3");
+        for object in remote {
+            println!(
+                "  {}  {}",
+                object.key,
+                zfs2s3::progress::format_bytes(object.size)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Show a progress bar per upload in progress until the task is aborted
+async fn show_progress() {
+    let bars = MultiProgress::new();
+    let mut shown: HashMap<String, ProgressBar> = HashMap::new();
+
+    loop {
+        let uploads = zfs2s3::progress::uploads_in_progress();
+        shown.retain(|key, bar| {
+            let running = uploads.iter().any(|u| &u.key == key);
+            if !running {
+                bar.finish_and_clear();
+            }
+            running
+        });
+
+        for upload in uploads {
+            let bar = shown.entry(upload.key.clone()).or_insert_with(|| {
+                let bar = bars.add(ProgressBar::no_length());
+                bar.set_message(upload.key.clone());
+                bar
+            });
+            match upload.estimated_bytes {
+                Some(estimate) => {
+                    bar.set_style(
+                        ProgressStyle::with_template(
+                            "{msg} [{bar:30}] {bytes}/~{total_bytes} {bytes_per_sec} ETA {eta}",
+                        )
+                        .expect("Invalid progress template")
+                        .progress_chars("=> "),
+                    );
+                    bar.set_length(estimate.max(upload.bytes_read));
+                }
+                None => bar.set_style(
+                    ProgressStyle::with_template("{spinner} {msg} {bytes} {bytes_per_sec}")
+                        .expect("Invalid progress template"),
+                ),
+            }
+            bar.set_position(upload.bytes_read);
+        }
+
+        sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// Wait for SIGTERM or SIGINT
+async fn terminate_signal() {
     let mut sigterm = signal(SignalKind::terminate()).expect("Failed to setup SIGTERM handler");
     let mut sigint = signal(SignalKind::interrupt()).expect("Failed to setup SIGINT handler");
 
     select! {
-        _ = sigterm.recv() => {
-            log::info!("Received SIGTERM")
-        },
-        _ = sigint.recv() => {
-            log::info!("Received SIGINT")
-        },
+        _ = sigterm.recv() => log::info!("Received SIGTERM"),
+        _ = sigint.recv() => log::info!("Received SIGINT"),
     }
 }
 
+/// Wait for SIGTERM or SIGINT. The configuration is reloaded on SIGHUP in the meantime.
+async fn shutdown_signal(daemon: &Daemon, args: &Args) {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to setup SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to setup SIGINT handler");
+    let mut sighup = signal(SignalKind::hangup()).expect("Failed to setup SIGHUP handler");
+
+    loop {
+        select! {
+            _ = sigterm.recv() => {
+                log::info!("Received SIGTERM");
+                break;
+            },
+            _ = sigint.recv() => {
+                log::info!("Received SIGINT");
+                break;
+            },
+            _ = sighup.recv() => {
+                log::info!("Received SIGHUP, reloading configuration");
+                reload_config(daemon, args).await;
+            },
+        }
+    }
+}
+
+/// Reload the configuration file, with the S3 client and the notification sinks. The current
+/// configuration is kept if the file is invalid. The state directory and the HTTP server are
+/// only changed by a restart.
+async fn reload_config(daemon: &Daemon, args: &Args) {
+    let reloaded = async {
+        let config = Config::load(Path::new(&args.config)).await?;
+        let s3_client = s3_client(args, &config)?;
+        daemon.notifier.reload(&config.notify)?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>((config, s3_client))
+    };
+    let (config, s3_client) = match reloaded.await {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            log::error!("Failed to reload configuration, keeping the current one: {e}");
+            return;
+        }
+    };
+
+    let current = Arc::clone(&daemon.config.borrow());
+    if config.state.dir != current.state.dir {
+        log::warn!("[state] dir changed, the daemon must be restarted to use it");
+    }
+    if config.http.listen != current.http.listen {
+        log::warn!("[http] listen changed, the daemon must be restarted to use it");
+    }
+    daemon.s3_client.send_replace(Arc::new(s3_client));
+    daemon.config.send_replace(Arc::new(config));
+    log::info!("Configuration reloaded from {}", args.config);
+}
+
 async fn run_scheduled_backups(
-    config: Arc<Config>,
-    s3_client: Arc<zfs2s3::s3::S3Client>,
-    cancel_token: CancellationToken,
-    op_lock: Arc<tokio::sync::Mutex<()>>,
+    daemon: Arc<Daemon>,
+    mut trigger: mpsc::Receiver<SnapshotType>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Run both Full and Incremental schedules in the same task to avoid having
-    // both schedules trigger backups at the same time.
-    let schedule = config.backup.schedule()?;
-    let incremental = config.backup.incremental()?;
+    let _task = daemon.status.task("backup");
+    let mut config_rx = daemon.config.subscribe();
 
-    while !cancel_token.is_cancelled() {
+    // Catch up on the full backups missed while the daemon was stopped
+    let config = Arc::clone(&daemon.config.borrow());
+    if let Some(window) = config.schedule.catch_up_window()? {
+        let timezone = config.schedule.timezone()?;
         let now = Utc::now();
-        let schedule_next = schedule
-            .after(&now)
-            .next()
-            .ok_or("No upcoming backup from schedule")?;
+        for policy in config.policies() {
+            let name = run_name(policy.name, &SnapshotType::Full);
+            let last_run = daemon.state.last_run(&name);
+            if let Some(missed) =
+                zfs2s3::schedule::missed_run(&policy.schedule()?, &timezone, last_run, now, window)
+            {
+                log::info!(
+                    "Catching up on the {name} backup missed at {}",
+                    zfs2s3::schedule::format_local(missed, &timezone)
+                );
+                let _lock = daemon.op_lock.lock().await;
+                run_backup(&daemon, &config, SnapshotType::Full, Some(policy.name)).await?;
+            }
+        }
+    }
 
-        let incremental_next = incremental
-            .after(&now)
-            .next()
-            .ok_or("No upcoming backup from schedule")?;
+    while !daemon.shutdown.requested.is_cancelled() {
+        // Use the latest configuration for every run
+        let config = Arc::clone(&config_rx.borrow_and_update());
+        let config = config.as_ref();
 
-        let schedule_duration = (schedule_next - now).to_std()?;
-        let incremental_duration = (incremental_next - now).to_std()?;
+        // Run the Full and Incremental schedules of every policy in the same task to avoid
+        // having several schedules trigger backups at the same time.
+        let now = Utc::now();
+        let timezone = config.schedule.timezone()?;
+        let delay =
+            zfs2s3::schedule::jitter(config.schedule.jitter()?, config.schedule.jitter_mode);
+        let mut runs = Vec::new();
+        for policy in config.policies() {
+            for snapshot_type in [SnapshotType::Full, SnapshotType::Incremental] {
+                let schedule = match snapshot_type {
+                    SnapshotType::Full => policy.schedule()?,
+                    SnapshotType::Incremental => policy.incremental()?,
+                };
+                let next = zfs2s3::schedule::next_run(&schedule, &timezone, now)
+                    .ok_or("No upcoming backup from schedule")?;
+                daemon
+                    .status
+                    .set_next_run(&run_name(policy.name, &snapshot_type), next + delay);
+                runs.push((next, policy.name.to_string(), snapshot_type));
+            }
+        }
 
-        let (duration, snapshot_type) = if incremental_duration < schedule_duration {
-            (incremental_duration, SnapshotType::Incremental)
-        } else {
-            (schedule_duration, SnapshotType::Full)
-        };
+        // Policies due at the same time are backed up one after the other. A full backup
+        // wins over an incremental backup of the same policy.
+        let next = runs
+            .iter()
+            .map(|(next, _, _)| *next)
+            .min()
+            .ok_or("No upcoming backup from schedule")?;
+        let mut due: Vec<(Option<String>, SnapshotType)> = runs
+            .into_iter()
+            .filter(|(time, _, _)| *time == next)
+            .map(|(_, policy, snapshot_type)| (Some(policy), snapshot_type))
+            .collect();
+        due.dedup_by(|a, b| a.0 == b.0);
+        let names: Vec<String> = due
+            .iter()
+            .map(|(policy, snapshot_type)| {
+                run_name(policy.as_deref().unwrap_or_default(), snapshot_type)
+            })
+            .collect();
+        log::info!(
+            "Next backup ({}) at {}",
+            names.join(", "),
+            zfs2s3::schedule::format_local(next + delay, &timezone)
+        );
 
-        select! {
-            _ = sleep(duration) => {}
-            _ = cancel_token.cancelled() => {
+        let due = select! {
+            _ = sleep((next - now).to_std()? + delay) => due,
+            Some(triggered) = trigger.recv() => {
+                log::info!("Triggered {triggered} backup");
+                vec![(None, triggered)]
+            }
+            Ok(()) = config_rx.changed() => {
+                log::info!("Rescheduling backups with the new configuration");
+                continue;
+            }
+            _ = daemon.shutdown.requested.cancelled() => {
                 break;
             }
-        }
+        };
 
         // Acquire operation lock
-        let _lock = op_lock.lock().await;
-
-        // Get volumes to back up
-        let mut volumes = zfs2s3::zfs::VolumeSnapshotMap::new()
-            .await?
-            .keep_volume_to_backup(&config);
-
-        if snapshot_type == SnapshotType::Incremental {
-            // Ensure there is at least one snapshot for each volume to back up
-            // before performing incremental backup
-            if let Err(e) = ensure_snapshots_for_volumes(&volumes).await {
-                log::error!("Failed to ensure snapshots for incremental backup: {e}");
-                continue;
-            }
+        let _lock = daemon.op_lock.lock().await;
+        for (policy, snapshot_type) in due {
+            run_backup(&daemon, config, snapshot_type, policy.as_deref()).await?;
         }
+    }
 
-        // Perform backup
-        if let Err(e) = zfs2s3::snapshot_volumes(&volumes, &snapshot_type).await {
-            log::error!("Failed to snapshot volumes: {e}");
-            continue;
-        }
-        if let Err(e) = volumes.refresh().await {
-            log::error!("Failed to refresh volume snapshots: {e}");
-            continue;
-        }
+    Ok(())
+}
 
-        // Sync local snapshots to S3. This step is to remediate issues from
-        // missed uploads.
-        if let Err(e) = zfs2s3::sync_snapshots(&s3_client, &volumes).await {
-            log::error!("Failed to sync snapshots to S3: {e}");
-        }
+/// Name of a backup schedule, as shown in the status and stored with its last run
+fn run_name(policy: &str, snapshot_type: &SnapshotType) -> String {
+    match policy {
+        DEFAULT_POLICY => snapshot_type.to_string(),
+        policy => format!("{policy} {snapshot_type}"),
     }
+}
 
+/// Run a backup and record the run of its schedule, or of the schedules of every policy for a
+/// backup of all the volumes
+async fn run_backup(
+    daemon: &Daemon,
+    config: &Config,
+    snapshot_type: SnapshotType,
+    policy: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let names: Vec<String> = match policy {
+        Some(policy) => vec![run_name(policy, &snapshot_type)],
+        None => config
+            .policies()
+            .iter()
+            .map(|p| run_name(p.name, &snapshot_type))
+            .collect(),
+    };
+    let snapshotted = zfs2s3::logging::run(
+        &format!("{snapshot_type}_backup"),
+        backup(daemon, config, snapshot_type, policy),
+    )
+    .await?;
+    // A failed run is caught up again after a restart
+    if snapshotted {
+        record_runs(daemon, &names).await;
+    }
     Ok(())
 }
 
+/// Persist the time of the last run of the schedules, to catch up on missed runs after a restart
+async fn record_runs(daemon: &Daemon, names: &[String]) {
+    let now = Utc::now();
+    for name in names {
+        daemon.state.set_last_run(name, now);
+    }
+    if let Err(e) = daemon.state.save().await {
+        log::error!("Failed to save state: {e}");
+    }
+}
+
+/// Snapshot the volumes of `policy`, or all the volumes. The snapshots are uploaded by the
+/// upload task. Returns whether the volumes were snapshotted, failures are logged and notified.
+async fn backup(
+    daemon: &Daemon,
+    config: &Config,
+    snapshot_type: SnapshotType,
+    policy: Option<&str>,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let _operation = daemon.status.start_operation(&match policy {
+        Some(policy) => format!("{snapshot_type} backup of policy {policy}"),
+        None => format!("{snapshot_type} backup"),
+    });
+
+    // Get volumes to back up
+    let volumes = zfs2s3::zfs::VolumeSnapshotMap::new()
+        .await?
+        .keep_volume_to_backup(config);
+    // Only the volumes of the policy are snapshotted
+    let targets = policy.map(|p| volumes.with_policy(p));
+    let targets = targets.as_ref().unwrap_or(&volumes);
+
+    if snapshot_type == SnapshotType::Incremental {
+        // Ensure there is at least one snapshot for each volume to back up
+        // before performing incremental backup
+        if let Err(e) = ensure_snapshots_for_volumes(targets, config).await {
+            log::error!("Failed to ensure snapshots for incremental backup: {e}");
+            daemon.notifier.snapshot_failure(&e.to_string()).await;
+            return Ok(false);
+        }
+    }
+
+    // Perform backup
+    if let Err(e) = zfs2s3::snapshot_volumes(targets, &snapshot_type, config).await {
+        log::error!("Failed to snapshot volumes: {e}");
+        daemon.notifier.snapshot_failure(&e.to_string()).await;
+        return Ok(false);
+    }
+    daemon.pending_uploads.notify_one();
+
+    Ok(true)
+}
+
 async fn run_cleanup(
-    config: Arc<Config>,
-    s3_client: Arc<zfs2s3::s3::S3Client>,
-    cancel_token: CancellationToken,
-    op_lock: Arc<tokio::sync::Mutex<()>>,
+    daemon: Arc<Daemon>,
+    mut trigger: mpsc::Receiver<()>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let schedule = config.cleanup.schedule()?;
-    while !cancel_token.is_cancelled() {
+    let _task = daemon.status.task("cleanup");
+    let mut config_rx = daemon.config.subscribe();
+
+    // Catch up on the cleanup missed while the daemon was stopped
+    let config = Arc::clone(&daemon.config.borrow());
+    if let Some(window) = config.schedule.catch_up_window()? {
+        let timezone = config.schedule.timezone()?;
+        let last_run = daemon.state.last_run("cleanup");
+        if let Some(missed) = zfs2s3::schedule::missed_run(
+            &config.cleanup.schedule()?,
+            &timezone,
+            last_run,
+            Utc::now(),
+            window,
+        ) {
+            log::info!(
+                "Catching up on the cleanup missed at {}",
+                zfs2s3::schedule::format_local(missed, &timezone)
+            );
+            let _sync_lock = daemon.sync_lock.lock().await;
+            let _lock = daemon.op_lock.lock().await;
+            zfs2s3::logging::run("cleanup", cleanup(&daemon, &config)).await?;
+            record_runs(&daemon, &["cleanup".to_string()]).await;
+        }
+    }
+
+    while !daemon.shutdown.requested.is_cancelled() {
+        let config = Arc::clone(&config_rx.borrow_and_update());
+        let config = config.as_ref();
+
+        let schedule = config.cleanup.schedule()?;
+        let timezone = config.schedule.timezone()?;
         let now = Utc::now();
-        let next = schedule
-            .after(&now)
-            .next()
+        let next = zfs2s3::schedule::next_run(&schedule, &timezone, now)
             .ok_or("No upcoming cleanup from schedule")?;
-        let duration = (next - now).to_std()?;
+        let delay =
+            zfs2s3::schedule::jitter(config.schedule.jitter()?, config.schedule.jitter_mode);
+        daemon.status.set_next_run("cleanup", next + delay);
+        log::info!(
+            "Next cleanup at {}",
+            zfs2s3::schedule::format_local(next + delay, &timezone)
+        );
+        let duration = (next - now).to_std()? + delay;
 
         select! {
             _ = sleep(duration) => {}
-            _ = cancel_token.cancelled() => {
+            Some(()) = trigger.recv() => {
+                log::info!("Triggered cleanup");
+            }
+            Ok(()) = config_rx.changed() => {
+                log::info!("Rescheduling cleanup with the new configuration");
+                continue;
+            }
+            _ = daemon.shutdown.requested.cancelled() => {
                 break;
             }
         }
 
-        // Acquire operation lock
-        let _lock = op_lock.lock().await;
+        // Acquire the locks, after the uploads in progress so that backups are not delayed
+        let _sync_lock = daemon.sync_lock.lock().await;
+        let _lock = daemon.op_lock.lock().await;
+        zfs2s3::logging::run("cleanup", cleanup(&daemon, config)).await?;
+        record_runs(&daemon, &["cleanup".to_string()]).await;
+    }
 
-        // Get volumes to back up
-        let mut volumes = zfs2s3::zfs::VolumeSnapshotMap::new()
-            .await?
-            .keep_volume_to_backup(&config);
+    Ok(())
+}
 
-        if let Err(e) = volumes.refresh().await {
-            log::error!("Failed to refresh volume snapshots: {e}");
-            continue;
-        }
+/// Apply the retention policy. The removed snapshots are deleted from S3 by the upload task.
+async fn cleanup(
+    daemon: &Daemon,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let _operation = daemon.status.start_operation("cleanup");
+
+    // Get volumes to back up
+    let mut volumes = zfs2s3::zfs::VolumeSnapshotMap::new()
+        .await?
+        .keep_volume_to_backup(config);
 
-        if let Err(e) = volumes.apply_retention_policy(&config).await {
+    if let Err(e) = volumes.refresh().await {
+        log::error!("Failed to refresh volume snapshots: {e}");
+        return Ok(());
+    }
+
+    match volumes.apply_retention_policy(config).await {
+        Ok(deleted) => daemon.notifier.retention(&deleted).await,
+        Err(e) => {
             log::error!("Failed to apply retention policy: {e}");
-            continue;
+            return Ok(());
         }
+    }
+    daemon.pending_uploads.notify_one();
+
+    Ok(())
+}
+
+/// Upload the snapshots missing from S3 and delete the objects of the destroyed snapshots,
+/// within the upload window. Snapshots are still taken on schedule while S3 is unavailable
+/// and uploaded once it is back.
+async fn run_uploads(daemon: Arc<Daemon>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let _task = daemon.status.task("upload");
+    let mut config_rx = daemon.config.subscribe();
 
-        if let Err(e) = zfs2s3::sync_snapshots(&s3_client, &volumes).await {
-            log::error!("Failed to delete snapshots from S3: {e}");
+    while !daemon.shutdown.requested.is_cancelled() {
+        let config = Arc::clone(&config_rx.borrow_and_update());
+        let window = config.upload.window()?;
+        let timezone = config.schedule.timezone()?;
+
+        // Wait for the upload window to open
+        let now = Utc::now();
+        if let Some(window) = &window
+            && !window.includes(now.with_timezone(&timezone))
+        {
+            let next = zfs2s3::schedule::next_run(window, &timezone, now)
+                .ok_or("No upcoming upload window from schedule")?;
+            daemon.status.set_next_run("upload", next);
+            log::info!(
+                "Uploads paused until {}",
+                zfs2s3::schedule::format_local(next, &timezone)
+            );
+            select! {
+                _ = sleep((next - now).to_std()?) => {}
+                Ok(()) = config_rx.changed() => {}
+                _ = daemon.shutdown.requested.cancelled() => {
+                    break;
+                }
+            }
             continue;
         }
+
+        {
+            let _sync_lock = daemon.sync_lock.lock().await;
+            zfs2s3::logging::run("upload", upload(&daemon, &config, window, timezone)).await?;
+        }
+
+        let interval = config.upload.interval()?;
+        daemon
+            .status
+            .set_next_run("upload", Utc::now() + chrono::Duration::from_std(interval)?);
+        select! {
+            _ = sleep(interval) => {}
+            _ = daemon.pending_uploads.notified() => {}
+            Ok(()) = config_rx.changed() => {}
+            _ = daemon.shutdown.requested.cancelled() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sync the local snapshots to S3. No new upload is started once the upload window closes.
+async fn upload(
+    daemon: &Daemon,
+    config: &Config,
+    window: Option<cron::Schedule>,
+    timezone: chrono_tz::Tz,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let volumes = match VolumeSnapshotMap::new().await {
+        Ok(volumes) => volumes.keep_volume_to_backup(config),
+        Err(e) => {
+            log::error!("Failed to list volume snapshots: {e}");
+            return Ok(());
+        }
+    };
+
+    let shutdown = Shutdown {
+        requested: daemon.shutdown.requested.child_token(),
+        abort: daemon.shutdown.abort.clone(),
+    };
+    let window_closed = window.map(|window| {
+        let requested = shutdown.requested.clone();
+        tokio::spawn(async move {
+            while window.includes(Utc::now().with_timezone(&timezone)) {
+                sleep(WINDOW_CHECK_INTERVAL).await;
+            }
+            log::info!("Upload window closed, uploads in progress are completed");
+            requested.cancel();
+        })
+    });
+
+    let s3_client = Arc::clone(&daemon.s3_client.borrow());
+    let result =
+        zfs2s3::sync_snapshots(&s3_client, &volumes, config, &daemon.state, &shutdown).await;
+    if let Some(window_closed) = window_closed {
+        window_closed.abort();
+    }
+
+    match result {
+        Ok(report) => daemon.notifier.sync_report(&report).await,
+        Err(e) => {
+            log::error!("Failed to sync snapshots to S3: {e}");
+            daemon
+                .notifier
+                .notify(NotificationEvent::UploadFailure, &e.to_string())
+                .await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_summary(daemon: Arc<Daemon>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config_rx = daemon.config.subscribe();
+
+    while !daemon.shutdown.requested.is_cancelled() {
+        let config = Arc::clone(&config_rx.borrow_and_update());
+        let schedule = config.notify.summary()?;
+        let timezone = config.schedule.timezone()?;
+
+        // Without a summary schedule, only wait for a new configuration
+        let now = Utc::now();
+        let duration = match schedule
+            .as_ref()
+            .and_then(|s| zfs2s3::schedule::next_run(s, &timezone, now))
+        {
+            Some(next) => Some((next - now).to_std()?),
+            None => None,
+        };
+        let wait = async {
+            match duration {
+                Some(duration) => sleep(duration).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        select! {
+            _ = wait => {}
+            Ok(()) = config_rx.changed() => {
+                continue;
+            }
+            _ = daemon.shutdown.requested.cancelled() => {
+                break;
+            }
+        }
+
+        daemon.notifier.summary().await;
     }
 
     Ok(())
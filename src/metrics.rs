@@ -0,0 +1,289 @@
+/// Process wide metrics exposed in the Prometheus text format.
+use crate::SnapshotType;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Upper bounds of the upload duration histogram buckets, in seconds
+const UPLOAD_DURATION_BUCKETS: [f64; 10] = [
+    1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 43200.0,
+];
+
+static METRICS: LazyLock<Mutex<Metrics>> = LazyLock::new(|| Mutex::new(Metrics::default()));
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; UPLOAD_DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        UPLOAD_DURATION_BUCKETS
+            .iter()
+            .zip(self.buckets.iter_mut())
+            .filter(|(bound, _)| value <= **bound)
+            .for_each(|(_, bucket)| *bucket += 1);
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    /// Snapshots created per snapshot type
+    snapshots_created: BTreeMap<String, u64>,
+    snapshot_failures: u64,
+    /// Uploads per volume and status
+    uploads: BTreeMap<(String, &'static str), u64>,
+    /// Bytes uploaded per volume
+    uploaded_bytes: BTreeMap<String, u64>,
+    upload_duration: Histogram,
+    /// Timestamp of the last successful upload per volume and snapshot type
+    last_success: BTreeMap<(String, String), i64>,
+    /// Number and total size of objects in the bucket, from the last listing
+    s3_objects: Option<(u64, u64)>,
+    /// Snapshots deleted by the retention policy per location (zfs or s3)
+    retention_deletions: BTreeMap<&'static str, u64>,
+}
+
+fn metrics() -> MutexGuard<'static, Metrics> {
+    METRICS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn snapshots_created(snapshot_type: &SnapshotType, count: usize) {
+    *metrics()
+        .snapshots_created
+        .entry(snapshot_type.to_string())
+        .or_default() += count as u64;
+}
+
+pub fn snapshot_failed() {
+    metrics().snapshot_failures += 1;
+}
+
+pub fn upload_succeeded(
+    volume: &str,
+    snapshot_type: &SnapshotType,
+    bytes: u64,
+    duration: Duration,
+    at: DateTime<Utc>,
+) {
+    let mut m = metrics();
+    *m.uploads
+        .entry((volume.to_string(), "succeeded"))
+        .or_default() += 1;
+    *m.uploaded_bytes.entry(volume.to_string()).or_default() += bytes;
+    m.upload_duration.observe(duration.as_secs_f64());
+    set_last_success(&mut m, volume, snapshot_type, at);
+}
+
+pub fn upload_failed(volume: &str) {
+    *metrics()
+        .uploads
+        .entry((volume.to_string(), "failed"))
+        .or_default() += 1;
+}
+
+/// Initialize the last successful upload of a volume, e.g. from the persisted state
+pub fn last_success(volume: &str, snapshot_type: &SnapshotType, at: DateTime<Utc>) {
+    set_last_success(&mut metrics(), volume, snapshot_type, at);
+}
+
+fn set_last_success(
+    m: &mut Metrics,
+    volume: &str,
+    snapshot_type: &SnapshotType,
+    at: DateTime<Utc>,
+) {
+    let entry = m
+        .last_success
+        .entry((volume.to_string(), snapshot_type.to_string()))
+        .or_default();
+    *entry = (*entry).max(at.timestamp());
+}
+
+pub fn s3_objects(count: u64, bytes: u64) {
+    metrics().s3_objects = Some((count, bytes));
+}
+
+pub fn retention_deleted(location: &'static str) {
+    *metrics().retention_deletions.entry(location).or_default() += 1;
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let m = metrics();
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "zfs2s3_snapshots_created_total",
+        "counter",
+        "Snapshots created",
+    );
+    for (snapshot_type, value) in m.snapshots_created.iter() {
+        let _ = writeln!(
+            out,
+            "zfs2s3_snapshots_created_total{{type=\"{snapshot_type}\"}} {value}"
+        );
+    }
+
+    header(
+        &mut out,
+        "zfs2s3_snapshot_failures_total",
+        "counter",
+        "Failed snapshots",
+    );
+    let _ = writeln!(
+        out,
+        "zfs2s3_snapshot_failures_total {}",
+        m.snapshot_failures
+    );
+
+    header(
+        &mut out,
+        "zfs2s3_uploads_total",
+        "counter",
+        "Snapshot uploads",
+    );
+    for ((volume, status), value) in m.uploads.iter() {
+        let _ = writeln!(
+            out,
+            "zfs2s3_uploads_total{{volume=\"{}\",status=\"{status}\"}} {value}",
+            escape(volume)
+        );
+    }
+
+    header(
+        &mut out,
+        "zfs2s3_uploaded_bytes_total",
+        "counter",
+        "Bytes uploaded to S3",
+    );
+    for (volume, value) in m.uploaded_bytes.iter() {
+        let _ = writeln!(
+            out,
+            "zfs2s3_uploaded_bytes_total{{volume=\"{}\"}} {value}",
+            escape(volume)
+        );
+    }
+
+    header(
+        &mut out,
+        "zfs2s3_upload_duration_seconds",
+        "histogram",
+        "Duration of successful uploads",
+    );
+    let histogram = &m.upload_duration;
+    for (bound, value) in UPLOAD_DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+        let _ = writeln!(
+            out,
+            "zfs2s3_upload_duration_seconds_bucket{{le=\"{bound}\"}} {value}"
+        );
+    }
+    let _ = writeln!(
+        out,
+        "zfs2s3_upload_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        histogram.count
+    );
+    let _ = writeln!(out, "zfs2s3_upload_duration_seconds_sum {}", histogram.sum);
+    let _ = writeln!(
+        out,
+        "zfs2s3_upload_duration_seconds_count {}",
+        histogram.count
+    );
+
+    header(
+        &mut out,
+        "zfs2s3_last_success_timestamp_seconds",
+        "gauge",
+        "Time of the last successful upload",
+    );
+    for ((volume, snapshot_type), value) in m.last_success.iter() {
+        let _ = writeln!(
+            out,
+            "zfs2s3_last_success_timestamp_seconds{{volume=\"{}\",type=\"{snapshot_type}\"}} {value}",
+            escape(volume)
+        );
+    }
+
+    if let Some((count, bytes)) = m.s3_objects {
+        header(
+            &mut out,
+            "zfs2s3_s3_objects",
+            "gauge",
+            "Objects in the bucket",
+        );
+        let _ = writeln!(out, "zfs2s3_s3_objects {count}");
+        header(
+            &mut out,
+            "zfs2s3_s3_objects_bytes",
+            "gauge",
+            "Total size of the objects in the bucket",
+        );
+        let _ = writeln!(out, "zfs2s3_s3_objects_bytes {bytes}");
+    }
+
+    header(
+        &mut out,
+        "zfs2s3_retention_deletions_total",
+        "counter",
+        "Snapshots deleted by the retention policy",
+    );
+    for (location, value) in m.retention_deletions.iter() {
+        let _ = writeln!(
+            out,
+            "zfs2s3_retention_deletions_total{{location=\"{location}\"}} {value}"
+        );
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(5.0);
+        histogram.observe(120.0);
+        assert_eq!(histogram.buckets[0], 0);
+        assert_eq!(histogram.buckets[1], 1);
+        assert_eq!(histogram.buckets[3], 2);
+        assert_eq!(histogram.count, 2);
+    }
+
+    #[test]
+    fn render_upload() {
+        upload_succeeded(
+            "pool/vm-\"test\"",
+            &SnapshotType::Full,
+            42,
+            Duration::from_secs(2),
+            DateTime::<Utc>::from_timestamp_secs(1700000000).unwrap(),
+        );
+        let out = render();
+        assert!(out.contains(r#"zfs2s3_uploaded_bytes_total{volume="pool/vm-\"test\""} 42"#));
+        assert!(out.contains(
+            r#"zfs2s3_last_success_timestamp_seconds{volume="pool/vm-\"test\"",type="full"} 1700000000"#
+        ));
+    }
+}
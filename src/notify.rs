@@ -0,0 +1,453 @@
+/// Notifications of backup outcomes to webhooks, chat services and email.
+use crate::SyncReport;
+use crate::config::{NotifyPolicy, Sink, SinkKind, SmtpTls};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    UploadFailure,
+    SnapshotFailure,
+    /// Local snapshots deleted by the retention policy
+    Retention,
+    /// S3 objects of the snapshots removed locally deleted by a sync
+    S3Deletion,
+    Summary,
+}
+
+impl Display for NotificationEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotificationEvent::UploadFailure => write!(f, "upload failure"),
+            NotificationEvent::SnapshotFailure => write!(f, "snapshot failure"),
+            NotificationEvent::Retention => write!(f, "retention"),
+            NotificationEvent::S3Deletion => write!(f, "S3 deletion"),
+            NotificationEvent::Summary => write!(f, "summary"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NotifyError {
+    HttpStatus(String, u16),
+}
+
+impl Display for NotifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotifyError::HttpStatus(url, status) => {
+                write!(f, "Notification to {} failed with status {}", url, status)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {}
+
+/// Activity accumulated for the periodic summary
+#[derive(Debug, Default)]
+struct Summary {
+    uploads: u64,
+    upload_failures: u64,
+    bytes: u64,
+    deleted: u64,
+    snapshot_failures: u64,
+}
+
+#[derive(Debug, Default)]
+struct RateLimit {
+    last_sent: Option<Instant>,
+    /// Notifications dropped since the last one sent
+    suppressed: u64,
+}
+
+/// Sinks and rate limit, replaced when the configuration is reloaded
+#[derive(Debug)]
+struct Settings {
+    sinks: Vec<Sink>,
+    rate_limit: Duration,
+}
+
+impl Settings {
+    fn new(config: &NotifyPolicy) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Settings {
+            sinks: config.sinks.clone(),
+            rate_limit: config.rate_limit()?,
+        })
+    }
+}
+
+/// Send notifications to the configured sinks.
+/// Failures to deliver a notification are logged and never interrupt a backup.
+pub struct Notifier {
+    settings: Mutex<Settings>,
+    hostname: String,
+    client: reqwest::Client,
+    rate_limits: Mutex<HashMap<NotificationEvent, RateLimit>>,
+    summary: Mutex<Summary>,
+}
+
+impl Notifier {
+    pub fn new(config: &NotifyPolicy) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Notifier {
+            settings: Mutex::new(Settings::new(config)?),
+            hostname: crate::hostname(),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()?,
+            rate_limits: Mutex::new(HashMap::new()),
+            summary: Mutex::new(Summary::default()),
+        })
+    }
+
+    /// Use the sinks and rate limit of a new configuration. The rate limits and the activity of
+    /// the summary are kept.
+    pub fn reload(
+        &self,
+        config: &NotifyPolicy,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        *lock(&self.settings) = Settings::new(config)?;
+        Ok(())
+    }
+
+    /// Send a notification, unless the same event was already sent within the rate limit
+    pub async fn notify(&self, event: NotificationEvent, message: &str) {
+        let sinks: Vec<Sink> = lock(&self.settings)
+            .sinks
+            .iter()
+            .filter(|s| s.accepts(event))
+            .cloned()
+            .collect();
+        if sinks.is_empty() {
+            return;
+        }
+
+        let Some(suppressed) = self.check_rate_limit(event) else {
+            log::debug!("Notification for {event} suppressed by rate limit");
+            return;
+        };
+
+        let mut message = message.to_string();
+        if suppressed > 0 {
+            message.push_str(&format!(
+                "\n({suppressed} similar notification(s) suppressed)"
+            ));
+        }
+
+        for sink in sinks.iter() {
+            let title = self.render(&sink.title, event, &message);
+            let body = self.render(&sink.message, event, &message);
+            if let Err(e) = self.send(sink, event, &title, &body).await {
+                log::error!("Failed to send notification: {e}");
+            }
+        }
+    }
+
+    /// Record a notification of `event` about to be sent. Returns the number of notifications
+    /// suppressed since the last one sent, or none if this one is suppressed too.
+    fn check_rate_limit(&self, event: NotificationEvent) -> Option<u64> {
+        let rate_limit = lock(&self.settings).rate_limit;
+        let mut rate_limits = lock(&self.rate_limits);
+        let limit = rate_limits.entry(event).or_default();
+        let limited = event != NotificationEvent::Summary
+            && limit.last_sent.is_some_and(|t| t.elapsed() < rate_limit);
+        if limited {
+            limit.suppressed += 1;
+            return None;
+        }
+        limit.last_sent = Some(Instant::now());
+        Some(std::mem::take(&mut limit.suppressed))
+    }
+
+    /// Notify upload failures and deletions of a sync, and record them for the summary
+    pub async fn sync_report(&self, report: &SyncReport) {
+        {
+            let mut summary = lock(&self.summary);
+            for upload in report.uploads.iter().filter(|u| !u.interrupted) {
+                match upload.error {
+                    None => {
+                        summary.uploads += 1;
+                        summary.bytes += upload.bytes;
+                    }
+                    Some(_) => summary.upload_failures += 1,
+                }
+            }
+            summary.deleted += report.deleted.len() as u64;
+            summary.upload_failures += report.volume_errors.len() as u64;
+        }
+
+        let failures: Vec<String> = report
+            .failed_uploads()
+            .map(|u| {
+                format!(
+                    "- {}: {}",
+                    u.snapshot,
+                    u.error.as_deref().unwrap_or_default()
+                )
+            })
+            .chain(
+                report
+                    .volume_errors
+                    .iter()
+                    .map(|(volume, error)| format!("- {volume}: {error}")),
+            )
+            .collect();
+        if !failures.is_empty() {
+            let message = format!("Failed to upload snapshots:\n{}", failures.join("\n"));
+            self.notify(NotificationEvent::UploadFailure, &message)
+                .await;
+        }
+
+        if !report.deleted.is_empty() {
+            let message = format!(
+                "Deleted from S3:\n{}",
+                report
+                    .deleted
+                    .iter()
+                    .map(|k| format!("- {k}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+            self.notify(NotificationEvent::S3Deletion, &message).await;
+        }
+    }
+
+    /// Notify snapshots deleted locally by the retention policy
+    pub async fn retention(&self, deleted: &[String]) {
+        if deleted.is_empty() {
+            return;
+        }
+        let message = format!(
+            "Deleted local snapshots:\n{}",
+            deleted
+                .iter()
+                .map(|s| format!("- {s}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+        self.notify(NotificationEvent::Retention, &message).await;
+    }
+
+    pub async fn snapshot_failure(&self, error: &str) {
+        lock(&self.summary).snapshot_failures += 1;
+        self.notify(NotificationEvent::SnapshotFailure, error).await;
+    }
+
+    /// Send the summary of the activity since the previous summary
+    pub async fn summary(&self) {
+        let summary = std::mem::take(&mut *lock(&self.summary));
+        let message = format!(
+            "Uploads: {} succeeded, {} failed ({} bytes)\nSnapshot failures: {}\nDeleted from S3: {}",
+            summary.uploads,
+            summary.upload_failures,
+            summary.bytes,
+            summary.snapshot_failures,
+            summary.deleted
+        );
+        self.notify(NotificationEvent::Summary, &message).await;
+    }
+
+    /// Replace the placeholders `{hostname}`, `{event}` and `{message}` of a template
+    fn render(&self, template: &str, event: NotificationEvent, message: &str) -> String {
+        template
+            .replace("{hostname}", &self.hostname)
+            .replace("{event}", &event.to_string())
+            .replace("{message}", message)
+    }
+
+    async fn send(
+        &self,
+        sink: &Sink,
+        event: NotificationEvent,
+        title: &str,
+        message: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let request = match &sink.kind {
+            SinkKind::Webhook { url } => self.client.post(url).json(&serde_json::json!({
+                "event": event,
+                "hostname": self.hostname,
+                "title": title,
+                "message": message,
+                "timestamp": chrono::Utc::now(),
+            })),
+            SinkKind::Slack { url } => self.client.post(url).json(&serde_json::json!({
+                "text": format!("*{title}*\n{message}"),
+            })),
+            SinkKind::Discord { url } => self.client.post(url).json(&serde_json::json!({
+                "content": format!("**{title}**\n{message}"),
+            })),
+            SinkKind::Gotify { url, token } => self
+                .client
+                .post(format!("{}/message", url.trim_end_matches('/')))
+                .header("X-Gotify-Key", token)
+                .json(&serde_json::json!({
+                    "title": title,
+                    "message": message,
+                    "priority": priority(event),
+                })),
+            SinkKind::Ntfy { url, token } => {
+                let request = self
+                    .client
+                    .post(url)
+                    .header("Title", title)
+                    .header("Priority", priority(event).to_string())
+                    .body(message.to_string());
+                match token {
+                    Some(token) => request.bearer_auth(token),
+                    None => request,
+                }
+            }
+            SinkKind::Smtp {
+                host,
+                port,
+                tls,
+                username,
+                password,
+                from,
+                to,
+            } => {
+                let mut builder = lettre::Message::builder()
+                    .from(from.parse::<Mailbox>()?)
+                    .subject(title);
+                for recipient in to {
+                    builder = builder.to(recipient.parse::<Mailbox>()?);
+                }
+                let email = builder.body(message.to_string())?;
+
+                let mut transport = match tls {
+                    SmtpTls::Starttls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+                    }
+                    SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+                    SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+                };
+                if let Some(port) = port {
+                    transport = transport.port(*port);
+                }
+                if let (Some(username), Some(password)) = (username, password) {
+                    transport =
+                        transport.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                transport.build().send(email).await?;
+                return Ok(());
+            }
+        };
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(NotifyError::HttpStatus(
+                response.url().to_string(),
+                response.status().as_u16(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl Sink {
+    fn accepts(&self, event: NotificationEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+/// Priority for services supporting it (Gotify and ntfy use close scales)
+fn priority(event: NotificationEvent) -> u8 {
+    match event {
+        NotificationEvent::UploadFailure | NotificationEvent::SnapshotFailure => 4,
+        NotificationEvent::Retention
+        | NotificationEvent::S3Deletion
+        | NotificationEvent::Summary => 2,
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod test_notify {
+    use super::*;
+    use crate::config::Config;
+
+    const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 5 * * Sun *"
+incremental = "0 30 4 * * Mon-Sat *"
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "3 months"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+
+[notify]
+rate_limit = "1h"
+
+[[notify.sink]]
+type = "ntfy"
+url = "http://127.0.0.1:9/zfs2s3"
+events = ["upload_failure"]
+title = "{event} on {hostname}"
+"#;
+
+    #[test]
+    fn render_template() {
+        let config = Config::try_from(CONFIG).unwrap();
+        let notifier = Notifier::new(&config.notify).unwrap();
+        let sink = lock(&notifier.settings).sinks[0].clone();
+        let title = notifier.render(&sink.title, NotificationEvent::UploadFailure, "boom");
+        assert_eq!(title, format!("upload failure on {}", notifier.hostname));
+        assert_eq!(
+            notifier.render(&sink.message, NotificationEvent::UploadFailure, "boom"),
+            "boom"
+        );
+        assert!(sink.accepts(NotificationEvent::UploadFailure));
+        assert!(!sink.accepts(NotificationEvent::Summary));
+    }
+
+    #[test]
+    fn rate_limited() {
+        let config = Config::try_from(CONFIG).unwrap();
+        let notifier = Notifier::new(&config.notify).unwrap();
+        let event = NotificationEvent::UploadFailure;
+        assert_eq!(notifier.check_rate_limit(event), Some(0));
+        assert_eq!(notifier.check_rate_limit(event), None);
+        assert_eq!(notifier.check_rate_limit(event), None);
+        assert_eq!(lock(&notifier.rate_limits)[&event].suppressed, 2);
+        // Summaries are never rate limited
+        assert_eq!(
+            notifier.check_rate_limit(NotificationEvent::Summary),
+            Some(0)
+        );
+        assert_eq!(
+            notifier.check_rate_limit(NotificationEvent::Summary),
+            Some(0)
+        );
+        // Local and S3 deletions are limited separately
+        assert_eq!(
+            notifier.check_rate_limit(NotificationEvent::Retention),
+            Some(0)
+        );
+        assert_eq!(
+            notifier.check_rate_limit(NotificationEvent::S3Deletion),
+            Some(0)
+        );
+
+        // A reload replaces the rate limit and keeps the notifications already sent
+        let reloaded = Config::try_from(&CONFIG.replace("\"1h\"", "\"0s\"")).unwrap();
+        notifier.reload(&reloaded.notify).unwrap();
+        assert_eq!(notifier.check_rate_limit(event), Some(2));
+    }
+}
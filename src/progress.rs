@@ -0,0 +1,247 @@
+/// Progress of the uploads in progress, reported in the logs and by the status API.
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Interval between two progress lines in the logs for an upload
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+static UPLOADS: LazyLock<Mutex<BTreeMap<String, Arc<Progress>>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+fn uploads() -> MutexGuard<'static, BTreeMap<String, Arc<Progress>>> {
+    UPLOADS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Debug)]
+struct Timing {
+    started: DateTime<Utc>,
+    start: Instant,
+    last_log: Instant,
+}
+
+/// Progress of a single upload
+#[derive(Debug)]
+pub struct Progress {
+    volume: String,
+    key: String,
+    /// Bytes read from `zfs send`
+    read: AtomicU64,
+    /// Bytes of the parts completed
+    sent: AtomicU64,
+    parts: AtomicU64,
+    /// Estimated size of the stream, 0 if unknown
+    estimate: AtomicU64,
+    timing: Mutex<Timing>,
+}
+
+/// Progress of an upload as reported by the status API
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadProgress {
+    pub volume: String,
+    pub key: String,
+    pub started: DateTime<Utc>,
+    pub bytes_read: u64,
+    pub bytes_sent: u64,
+    pub parts_completed: u64,
+    pub estimated_bytes: Option<u64>,
+    /// Bytes read per second since the start
+    pub rate: f64,
+    pub eta_seconds: Option<u64>,
+}
+
+impl Progress {
+    fn new(volume: &str, key: &str) -> Self {
+        let now = Instant::now();
+        Progress {
+            volume: volume.to_string(),
+            key: key.to_string(),
+            read: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            parts: AtomicU64::new(0),
+            estimate: AtomicU64::new(0),
+            timing: Mutex::new(Timing {
+                started: Utc::now(),
+                start: now,
+                last_log: now,
+            }),
+        }
+    }
+
+    fn timing(&self) -> MutexGuard<'_, Timing> {
+        self.timing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start a new attempt of the upload
+    pub fn restart(&self, estimate: Option<u64>) {
+        self.read.store(0, Ordering::Relaxed);
+        self.sent.store(0, Ordering::Relaxed);
+        self.parts.store(0, Ordering::Relaxed);
+        self.estimate
+            .store(estimate.unwrap_or_default(), Ordering::Relaxed);
+        let now = Instant::now();
+        *self.timing() = Timing {
+            started: Utc::now(),
+            start: now,
+            last_log: now,
+        };
+    }
+
+    /// Record bytes read from the stream, and log the progress periodically
+    pub fn read(&self, bytes: usize) {
+        self.read.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        let log = {
+            let mut timing = self.timing();
+            let log = timing.last_log.elapsed() >= LOG_INTERVAL;
+            if log {
+                timing.last_log = Instant::now();
+            }
+            log
+        };
+        if log {
+            let report = self.report();
+            log::info!(
+                "Uploading {}: {}{} read, {} sent in {} part(s), {}/s{}",
+                report.key,
+                format_bytes(report.bytes_read),
+                report
+                    .estimated_bytes
+                    .map(|e| format!(" of ~{}", format_bytes(e)))
+                    .unwrap_or_default(),
+                format_bytes(report.bytes_sent),
+                report.parts_completed,
+                format_bytes(report.rate as u64),
+                report
+                    .eta_seconds
+                    .map(|eta| format!(
+                        ", ETA {}",
+                        humantime::format_duration(Duration::from_secs(eta))
+                    ))
+                    .unwrap_or_default(),
+            );
+        }
+    }
+
+    /// Record a part sent to S3
+    pub fn part_completed(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.parts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self) -> UploadProgress {
+        let (started, elapsed) = {
+            let timing = self.timing();
+            (timing.started, timing.start.elapsed())
+        };
+        let read = self.read.load(Ordering::Relaxed);
+        let estimate = self.estimate.load(Ordering::Relaxed);
+        let rate = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => read as f64 / secs,
+            _ => 0.0,
+        };
+        let eta_seconds = (estimate > 0 && rate > 0.0)
+            .then(|| (estimate.saturating_sub(read) as f64 / rate) as u64);
+
+        UploadProgress {
+            volume: self.volume.clone(),
+            key: self.key.clone(),
+            started,
+            bytes_read: read,
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+            parts_completed: self.parts.load(Ordering::Relaxed),
+            estimated_bytes: (estimate > 0).then_some(estimate),
+            rate,
+            eta_seconds,
+        }
+    }
+}
+
+/// Registration of an upload in progress, removed when dropped
+pub struct ProgressGuard {
+    progress: Arc<Progress>,
+}
+
+impl std::ops::Deref for ProgressGuard {
+    type Target = Arc<Progress>;
+
+    fn deref(&self) -> &Arc<Progress> {
+        &self.progress
+    }
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        uploads().remove(&self.progress.key);
+    }
+}
+
+/// Track the progress of the upload of `key` until the guard is dropped
+pub fn track(volume: &str, key: &str) -> ProgressGuard {
+    let progress = Arc::new(Progress::new(volume, key));
+    uploads().insert(key.to_string(), Arc::clone(&progress));
+    ProgressGuard { progress }
+}
+
+/// Progress of all uploads in progress, sorted by key
+pub fn uploads_in_progress() -> Vec<UploadProgress> {
+    uploads().values().map(|p| p.report()).collect()
+}
+
+/// Format a number of bytes with a binary unit, e.g. "1.5 GiB"
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod test_progress {
+    use super::*;
+
+    #[test]
+    fn registered_until_dropped() {
+        let guard = track("pool/vm-100-disk-0", "vm-100-disk-0@test-progress");
+        guard.restart(Some(1000));
+        guard.read(400);
+        guard.part_completed(300);
+        let report = uploads_in_progress()
+            .into_iter()
+            .find(|p| p.key == "vm-100-disk-0@test-progress")
+            .unwrap();
+        assert_eq!(report.bytes_read, 400);
+        assert_eq!(report.bytes_sent, 300);
+        assert_eq!(report.parts_completed, 1);
+        assert_eq!(report.estimated_bytes, Some(1000));
+
+        drop(guard);
+        assert!(
+            !uploads_in_progress()
+                .iter()
+                .any(|p| p.key == "vm-100-disk-0@test-progress")
+        );
+    }
+
+    #[test]
+    fn bytes_are_formatted() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -0,0 +1,154 @@
+/// Retry of operations with exponential backoff. S3 requests are retried by the S3 client, only
+/// the failures of `zfs send` restart an upload from the beginning.
+use crate::config::{RetryOn, RetryPolicy};
+use crate::zfs::ZfsError;
+use rand::Rng;
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+/// Kind of a retriable error, none if the error is not retriable. S3 errors are not retried
+/// here: the client already retried the request, so they are either permanent or persistent.
+fn classify(error: &(dyn Error + 'static)) -> Option<RetryOn> {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.downcast_ref::<object_store::Error>().is_some() {
+            return None;
+        }
+        if let Some(ZfsError::SendFailed(_)) = error.downcast_ref::<ZfsError>() {
+            return Some(RetryOn::ZfsSend);
+        }
+        // Errors from streams are wrapped in io::Error
+        if let Some(inner) = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+        {
+            source = Some(inner);
+            continue;
+        }
+        source = error.source();
+    }
+    None
+}
+
+/// Delay before the next attempt. With jitter, the delay is picked between half and
+/// the full backoff.
+fn delay(backoff: Duration, jitter: bool) -> Duration {
+    if jitter {
+        backoff.mul_f64(rand::rng().random_range(0.5..=1.0))
+    } else {
+        backoff
+    }
+}
+
+/// Run `operation` until it succeeds, it fails with an error which is not retriable, or the
+/// attempts of the policy are exhausted. Retries stop when `cancel` is cancelled.
+pub async fn retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    name: &str,
+    cancel: &CancellationToken,
+    mut operation: F,
+) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+{
+    let attempts = policy.attempts();
+    let mut backoff = policy.initial_backoff()?;
+    let max_backoff = policy.max_backoff()?;
+
+    let mut attempt = 1;
+    loop {
+        let error = match operation().await {
+            Ok(value) => {
+                if attempt > 1 {
+                    log::info!("{name} succeeded after {attempt} attempts");
+                }
+                return Ok(value);
+            }
+            Err(e) => e,
+        };
+
+        let retriable = classify(error.as_ref()).is_some_and(|kind| policy.retries(kind));
+        if !retriable || attempt >= attempts || cancel.is_cancelled() {
+            return Err(error);
+        }
+
+        let delay = delay(backoff, policy.jitter());
+        log::warn!(
+            "{name} failed (attempt {attempt}/{attempts}), retrying in {}: {error}",
+            humantime::format_duration(Duration::from_millis(delay.as_millis() as u64))
+        );
+        select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel.cancelled() => return Err(error),
+        }
+        backoff = (backoff * 2).min(max_backoff);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod test_retry {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn policy(toml: &str) -> RetryPolicy {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn classify_errors() {
+        let send: Box<dyn Error + Send + Sync> = Box::new(std::io::Error::other(
+            ZfsError::SendFailed(ExitStatus::from_raw(256)),
+        ));
+        assert_eq!(classify(send.as_ref()), Some(RetryOn::ZfsSend));
+
+        let s3 = object_store::Error::Generic {
+            store: "S3",
+            source: "Server returned non-2xx status code: 500".into(),
+        };
+        assert_eq!(classify(&s3), None);
+
+        let not_found = object_store::Error::NotFound {
+            path: "a".to_string(),
+            source: "missing".into(),
+        };
+        assert_eq!(classify(&not_found), None);
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let policy = policy("attempts = 3\ninitial_backoff = \"1ms\"\nmax_backoff = \"2ms\"");
+        let mut calls = 0;
+        let result = retry(&policy, "Test", &CancellationToken::new(), || {
+            calls += 1;
+            let calls = calls;
+            async move {
+                if calls < 3 {
+                    Err(ZfsError::SendFailed(ExitStatus::from_raw(256)).into())
+                } else {
+                    Ok(calls)
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn error_not_retried() {
+        let policy = policy("retry_on = [\"s3\"]\ninitial_backoff = \"1ms\"");
+        let mut calls = 0;
+        let result: Result<(), _> = retry(&policy, "Test", &CancellationToken::new(), || {
+            calls += 1;
+            async { Err(ZfsError::SendFailed(ExitStatus::from_raw(256)).into()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
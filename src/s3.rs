@@ -1,8 +1,158 @@
-use futures::stream::StreamExt;
-use object_store::WriteMultipart;
+use crate::config::{RetryOn, RetryPolicy};
+use crate::credentials::Credentials;
+use crate::progress::Progress;
+use crate::throttle::RateLimiter;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
 use object_store::aws::AmazonS3Builder;
+use object_store::{
+    BackoffConfig, MultipartUpload, PutPayload, PutResult, RetryConfig, UploadPart, WriteMultipart,
+};
 use object_store::{ObjectStore, path::Path as ObjectPath};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug)]
+pub enum S3Error {
+    /// The upload of the key was aborted by a shutdown
+    Interrupted(String),
+}
+
+impl Display for S3Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            S3Error::Interrupted(key) => write!(f, "Upload of {} interrupted", key),
+        }
+    }
+}
+
+impl std::error::Error for S3Error {}
+
+// S3 multipart has an object size of max 5TB, with each part between 5MB and 5GB.
+// The max number of parts is 10,000.
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024; // 5MB
+const MAX_PART_SIZE: usize = 5 * 1024 * 1024 * 1024; // 5GB
+const MAX_PARTS: u64 = 10_000;
+// When the size is unknown, a part size of 500MB covers the max object size.
+const DEFAULT_PART_SIZE: usize = 500 * 1024 * 1024; // 500MB
+// Pipes return at most a few KB per read, the buffer does not need to hold a part.
+const READ_BUFFER_SIZE: usize = 1024 * 1024; // 1MB
+// Streams estimated or found to be smaller than this are sent with a single PUT.
+const SINGLE_PUT_SIZE: usize = MIN_PART_SIZE;
+
+/// Smallest part size keeping the upload under the max number of parts.
+/// A margin is kept since the estimated size of a stream is not exact.
+fn part_size(size_hint: Option<u64>) -> usize {
+    match size_hint {
+        Some(size) => {
+            let size = size.saturating_add(size / 4);
+            (size.div_ceil(MAX_PARTS) as usize).clamp(MIN_PART_SIZE, MAX_PART_SIZE)
+        }
+        None => DEFAULT_PART_SIZE,
+    }
+}
+
+/// Read the next chunk of the stream through the rate limiters of the upload
+async fn read_chunk<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buf: &mut [u8],
+    key: &str,
+    options: &UploadOptions<'_>,
+    progress: &Progress,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let n = select! {
+        n = stream.read(buf) => n?,
+        _ = options.abort.cancelled() => return Err(S3Error::Interrupted(key.to_string()).into()),
+    };
+    for limiter in options.limiters.iter() {
+        select! {
+            _ = limiter.acquire(n) => {}
+            _ = options.abort.cancelled() => return Err(S3Error::Interrupted(key.to_string()).into()),
+        }
+    }
+    progress.read(n);
+    Ok(n)
+}
+
+/// Multipart upload reporting the parts completed. It is aborted when dropped before being
+/// completed, e.g. when interrupted while finishing, so that its parts do not stay in the bucket.
+#[derive(Debug)]
+struct ProgressUpload {
+    /// None once the upload is completed or aborted
+    upload: Option<Box<dyn MultipartUpload>>,
+    progress: Arc<Progress>,
+}
+
+impl ProgressUpload {
+    fn upload(&mut self) -> &mut Box<dyn MultipartUpload> {
+        self.upload
+            .as_mut()
+            .expect("Multipart upload already completed or aborted")
+    }
+}
+
+impl Drop for ProgressUpload {
+    fn drop(&mut self) {
+        if let Some(mut upload) = self.upload.take()
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            runtime.spawn(async move {
+                if let Err(e) = upload.abort().await {
+                    log::warn!("Failed to abort multipart upload: {e}");
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl MultipartUpload for ProgressUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        let size = data.content_length();
+        let part = self.upload().put_part(data);
+        let progress = Arc::clone(&self.progress);
+        Box::pin(async move {
+            part.await?;
+            progress.part_completed(size);
+            Ok(())
+        })
+    }
+
+    async fn complete(&mut self) -> object_store::Result<PutResult> {
+        let result = self.upload().complete().await;
+        if result.is_ok() {
+            self.upload = None;
+        }
+        result
+    }
+
+    async fn abort(&mut self) -> object_store::Result<()> {
+        let result = self.upload().abort().await;
+        self.upload = None;
+        result
+    }
+}
+
+/// Object stored in the bucket
+#[derive(Debug, Clone)]
+pub struct S3Object {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Settings of a single upload
+pub struct UploadOptions<'a> {
+    /// Number of parts sent at the same time
+    pub max_parallel_parts: usize,
+    /// Rate limiters the stream is read through
+    pub limiters: Vec<Arc<RateLimiter>>,
+    /// Cancelled to abort the upload
+    pub abort: &'a CancellationToken,
+}
 
 pub struct S3Client {
     store: Box<dyn ObjectStore>,
@@ -13,16 +163,34 @@ impl S3Client {
         url: &str,
         region: &str,
         bucket: &str,
-        key_id: &str,
-        secret_key: &str,
+        credentials: &Credentials,
+        retry: &RetryPolicy,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let store = AmazonS3Builder::new()
+        // Requests, including each part of a multipart upload, are retried by the client
+        let retry_config = RetryConfig {
+            backoff: BackoffConfig {
+                init_backoff: retry.initial_backoff()?,
+                max_backoff: retry.max_backoff()?,
+                base: 2.0,
+            },
+            max_retries: match retry.retries(RetryOn::S3) {
+                true => retry.attempts() as usize - 1,
+                false => 0,
+            },
+            ..Default::default()
+        };
+
+        // Without credentials, the client looks them up in the environment and instance metadata
+        let builder = match credentials.provider() {
+            Some(provider) => AmazonS3Builder::new().with_credentials(provider),
+            None => AmazonS3Builder::from_env(),
+        };
+        let store = builder
             .with_endpoint(url)
             .with_allow_http(true)
             .with_region(region)
             .with_bucket_name(bucket)
-            .with_access_key_id(key_id)
-            .with_secret_access_key(secret_key)
+            .with_retry(retry_config)
             .build()?;
 
         Ok(S3Client {
@@ -30,46 +198,135 @@ impl S3Client {
         })
     }
 
-    // Stream any AsyncRead (e.g., ChildStdout) without buffering entire output
+    // Stream any AsyncRead (e.g., ChildStdout) without buffering entire output.
+    // `size_hint` is the estimated size of the stream, used to pick the part size.
+    // Streams estimated to be small, or without an estimate, are buffered up to
+    // `SINGLE_PUT_SIZE` and sent with a single PUT if they end by then. Otherwise the memory
+    // used is the part being filled plus `max_parallel_parts` parts being sent.
+    // When `options.abort` is cancelled, the multipart upload is aborted so that no incomplete
+    // upload is left in the bucket.
     pub async fn upload_stream<R: AsyncRead + Unpin>(
         &self,
         mut stream: R,
         key: &str,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // S3 multipart has an object size of max 5TB, with each part between 5MB and 5GB.
-        // The max number of parts is 10,000.
-        // Since we do not know the total size in advance, we will use a part size of 500MB to
-        // cover the max use case.
-        const UPLOAD_BUFFER_SIZE: usize = 500 * 1024 * 1024; // 500MB
-        const MAX_CONCURRENT_UPLOADS: usize = 1; // Number of concurrent uploads
-
-        let upload = self.store.put_multipart(&ObjectPath::from(key)).await?;
-        let mut writer = WriteMultipart::new_with_chunk_size(upload, UPLOAD_BUFFER_SIZE);
-
-        let mut buf = vec![0u8; UPLOAD_BUFFER_SIZE];
-        loop {
-            let n = stream.read(&mut buf).await?;
+        size_hint: Option<u64>,
+        options: &UploadOptions<'_>,
+        progress: &Arc<Progress>,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let path = ObjectPath::from(key);
+        let part_size = part_size(size_hint);
+        progress.restart(size_hint);
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+
+        // Buffer the beginning of a small stream to find out if a multipart upload is needed
+        let single_put = match size_hint {
+            Some(size) if size > SINGLE_PUT_SIZE as u64 => 0,
+            _ => SINGLE_PUT_SIZE,
+        };
+        let mut first_part = Vec::with_capacity(single_put);
+        while first_part.len() < single_put {
+            let max = buf.len().min(single_put - first_part.len());
+            let n = read_chunk(&mut stream, &mut buf[..max], key, options, progress).await?;
             if n == 0 {
-                break;
+                let total = first_part.len() as u64;
+                select! {
+                    r = self.store.put(&path, first_part.into()) => r?,
+                    _ = options.abort.cancelled() => return Err(S3Error::Interrupted(key.to_string()).into()),
+                };
+                progress.part_completed(total as usize);
+                return Ok(total);
             }
-            writer.wait_for_capacity(MAX_CONCURRENT_UPLOADS).await?;
-            writer.write(&buf[..n]);
+            first_part.extend_from_slice(&buf[..n]);
         }
 
-        writer.finish().await?;
-        Ok(())
+        log::debug!("Uploading {key} in parts of {part_size} bytes");
+        let upload = Box::new(ProgressUpload {
+            upload: Some(self.store.put_multipart(&path).await?),
+            progress: Arc::clone(progress),
+        });
+        let mut writer = WriteMultipart::new_with_chunk_size(upload, part_size);
+        let mut total = first_part.len() as u64;
+        writer.write(&first_part);
+        drop(first_part);
+
+        let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+            loop {
+                let n = read_chunk(&mut stream, &mut buf, key, options, progress).await?;
+                if n == 0 {
+                    break;
+                }
+                select! {
+                    r = writer.wait_for_capacity(options.max_parallel_parts) => r?,
+                    _ = options.abort.cancelled() => return Err(S3Error::Interrupted(key.to_string()).into()),
+                }
+                writer.write(&buf[..n]);
+                total += n as u64;
+            }
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            // Do not leave the parts already uploaded in the bucket
+            if let Err(abort_error) = writer.abort().await {
+                log::warn!("Failed to abort multipart upload of {key}: {abort_error}");
+            }
+            return Err(e);
+        }
+
+        // The upload is aborted if completing it fails or is interrupted
+        select! {
+            r = writer.finish() => r?,
+            _ = options.abort.cancelled() => return Err(S3Error::Interrupted(key.to_string()).into()),
+        };
+        Ok(total)
     }
 
     pub async fn list_objects(
         &self,
-    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
-        let mut object_keys = Vec::new();
+    ) -> Result<Vec<S3Object>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut objects = Vec::new();
         let mut stream = self.store.list(None);
 
         while let Some(meta) = stream.next().await.transpose()? {
-            object_keys.push(meta.location.to_string());
+            objects.push(S3Object {
+                key: meta.location.to_string(),
+                size: meta.size,
+            });
         }
-        Ok(object_keys)
+        Ok(objects)
+    }
+
+    /// Stream the content of an object
+    pub async fn download_stream(
+        &self,
+        key: &str,
+    ) -> Result<
+        BoxStream<'static, object_store::Result<Bytes>>,
+        Box<dyn std::error::Error + Send + Sync>,
+    > {
+        let result = self.store.get(&ObjectPath::from(key)).await?;
+        Ok(result.into_stream())
+    }
+
+    /// Check the credentials and permissions by writing, reading and deleting a probe object.
+    /// Returns the key of the probe object.
+    pub async fn probe(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let key = format!(".zfs2s3-probe-{:08x}", rand::random::<u32>());
+        let path = ObjectPath::from(key.as_str());
+        let content = Bytes::from(format!("zfs2s3 probe {key}"));
+
+        self.store
+            .put(&path, PutPayload::from_bytes(content.clone()))
+            .await?;
+        let read = self.store.get(&path).await?.bytes().await;
+        // Always try to clean up, even if the read failed
+        self.store.delete(&path).await?;
+
+        if read? != content {
+            return Err(format!("Probe object {key} read back with a different content").into());
+        }
+        Ok(key)
     }
 
     pub async fn delete_object(
@@ -80,3 +337,19 @@ impl S3Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test_s3 {
+    use super::*;
+
+    #[test]
+    fn part_size_from_estimate() {
+        assert_eq!(part_size(None), DEFAULT_PART_SIZE);
+        assert_eq!(part_size(Some(10 * 1024)), MIN_PART_SIZE);
+        // 1TB with a 25% margin in 10,000 parts
+        let size = 1024 * 1024 * 1024 * 1024;
+        assert_eq!(part_size(Some(size)), 137438954);
+        assert!(part_size(Some(size)) as u64 * MAX_PARTS > size);
+        assert_eq!(part_size(Some(u64::MAX)), MAX_PART_SIZE);
+    }
+}
@@ -0,0 +1,171 @@
+/// Helpers to run the schedules of the daemon. Schedules follow the wall clock of their time
+/// zone: a time skipped when the clocks go forward runs right after the change, and a time
+/// repeated when the clocks go back runs once.
+use crate::config::JitterMode;
+use chrono::offset::LocalResult;
+use chrono::{DateTime, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::time::Duration;
+
+/// Next run of `schedule` after `after`
+pub fn next_run(schedule: &Schedule, timezone: &Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    // Iterate over the times of the wall clock, represented as UTC
+    let local = after.with_timezone(timezone).naive_local().and_utc();
+    schedule
+        .after(&local)
+        .map(|time| resolve(timezone, time.naive_utc()))
+        .find(|time| *time > after)
+}
+
+/// Latest run of `schedule` before `before`
+pub fn previous_run(
+    schedule: &Schedule,
+    timezone: &Tz,
+    before: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let local = before.with_timezone(timezone).naive_local().and_utc();
+    schedule
+        .after(&local)
+        .rev()
+        .map(|time| resolve(timezone, time.naive_utc()))
+        .find(|time| *time < before)
+}
+
+/// Instant of a time of the wall clock of `timezone`
+fn resolve(timezone: &Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    match timezone.from_local_datetime(&local) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time.with_timezone(&Utc),
+        // Skipped when the clocks went forward, use the offset before the change
+        LocalResult::None => {
+            let before = timezone
+                .offset_from_utc_datetime(&(local - chrono::Duration::days(1)))
+                .fix();
+            (local - chrono::Duration::seconds(before.local_minus_utc() as i64)).and_utc()
+        }
+    }
+}
+
+/// Time in the time zone of the schedules, for the logs
+pub fn format_local(time: DateTime<Utc>, timezone: &Tz) -> String {
+    time.with_timezone(timezone)
+        .format("%Y-%m-%d %H:%M:%S %Z")
+        .to_string()
+}
+
+/// Latest occurrence of `schedule` before `now` if it was missed: it is less than `window` ago
+/// and the schedule did not run since. Nothing is missed when the schedule never ran.
+pub fn missed_run(
+    schedule: &Schedule,
+    timezone: &Tz,
+    last_run: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    window: chrono::Duration,
+) -> Option<DateTime<Utc>> {
+    let previous = previous_run(schedule, timezone, now)?;
+    (now - previous <= window && last_run? < previous).then_some(previous)
+}
+
+/// Delay of a scheduled run, at most `max`
+pub fn jitter(max: Duration, mode: JitterMode) -> Duration {
+    jitter_of_host(max, mode, &crate::hostname())
+}
+
+fn jitter_of_host(max: Duration, mode: JitterMode, hostname: &str) -> Duration {
+    let max = max.as_millis() as u64;
+    if max == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(match mode {
+        JitterMode::Random => rand::random_range(0..=max),
+        JitterMode::Hostname => fnv1a(hostname) % (max + 1),
+    })
+}
+
+/// FNV-1a hash, which unlike the hasher of the standard library does not change between builds
+fn fnv1a(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod test_schedule {
+    use super::*;
+
+    #[test]
+    fn jitter_within_max() {
+        let max = Duration::from_secs(600);
+        let delay = jitter_of_host(max, JitterMode::Hostname, "pve-01");
+        assert!(delay <= max);
+        assert_eq!(delay, jitter_of_host(max, JitterMode::Hostname, "pve-01"));
+        assert_ne!(delay, jitter_of_host(max, JitterMode::Hostname, "pve-02"));
+        assert!(jitter_of_host(max, JitterMode::Random, "pve-01") <= max);
+        assert_eq!(
+            jitter_of_host(Duration::ZERO, JitterMode::Random, "pve-01"),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn missed_run_within_window() {
+        // Sundays at midnight
+        let schedule: Schedule = "0 0 0 * * Sun *".parse().unwrap();
+        let sunday = Utc.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap();
+        let monday = Utc.with_ymd_and_hms(2025, 6, 2, 8, 0, 0).unwrap();
+        let week_before = sunday - chrono::Duration::weeks(1);
+        let window = chrono::Duration::days(2);
+        let utc = Tz::UTC;
+
+        assert_eq!(
+            missed_run(&schedule, &utc, Some(week_before), monday, window),
+            Some(sunday)
+        );
+        assert_eq!(
+            missed_run(&schedule, &utc, Some(sunday), monday, window),
+            None
+        );
+        assert_eq!(missed_run(&schedule, &utc, None, monday, window), None);
+        assert_eq!(
+            missed_run(
+                &schedule,
+                &utc,
+                Some(week_before),
+                monday,
+                chrono::Duration::hours(1)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn runs_follow_daylight_saving_time() {
+        let paris: Tz = "Europe/Paris".parse().unwrap();
+        // Every day at 2:30, which does not exist on 2025-03-30 and exists twice on 2025-10-26
+        let schedule: Schedule = "0 30 2 * * * *".parse().unwrap();
+        let next = |after| next_run(&schedule, &paris, after).unwrap();
+
+        let winter = next(Utc.with_ymd_and_hms(2025, 3, 28, 12, 0, 0).unwrap());
+        assert_eq!(winter, Utc.with_ymd_and_hms(2025, 3, 29, 1, 30, 0).unwrap());
+        // Skipped time, run right after the change at 3:30 CEST
+        let spring = next(winter);
+        assert_eq!(spring, Utc.with_ymd_and_hms(2025, 3, 30, 1, 30, 0).unwrap());
+        assert_eq!(format_local(spring, &paris), "2025-03-30 03:30:00 CEST");
+        assert_eq!(
+            next(spring),
+            Utc.with_ymd_and_hms(2025, 3, 31, 0, 30, 0).unwrap()
+        );
+
+        // Repeated time, run once at 2:30 CEST
+        let autumn = next(Utc.with_ymd_and_hms(2025, 10, 25, 12, 0, 0).unwrap());
+        assert_eq!(
+            autumn,
+            Utc.with_ymd_and_hms(2025, 10, 26, 0, 30, 0).unwrap()
+        );
+        assert_eq!(
+            next(autumn),
+            Utc.with_ymd_and_hms(2025, 10, 27, 1, 30, 0).unwrap()
+        );
+        assert_eq!(previous_run(&schedule, &paris, next(autumn)), Some(autumn));
+    }
+}
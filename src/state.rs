@@ -0,0 +1,266 @@
+/// Local state persisted between runs: upload attempts and their outcome, the last known
+/// listing of S3 objects and the last runs of the schedules.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const STATE_FILE: &str = "state.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadStatus {
+    Succeeded,
+    Failed,
+    /// Aborted by a shutdown, uploaded again by the next sync
+    Interrupted,
+}
+
+/// Latest upload attempt of a snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadRecord {
+    pub volume: String,
+    pub snapshot: String,
+    pub key: String,
+    /// Key of the snapshot this incremental snapshot is based on, none for full snapshots
+    pub parent: Option<String>,
+    pub started: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// Bytes sent to S3, up to the failure for failed uploads
+    pub bytes: u64,
+    /// Number of attempts since the last success
+    pub attempts: u32,
+    pub status: UploadStatus,
+    pub error: Option<String>,
+}
+
+impl UploadRecord {
+    /// Time the upload ended
+    pub fn finished(&self) -> DateTime<Utc> {
+        self.started + chrono::Duration::milliseconds(self.duration_ms as i64)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// Latest upload record per S3 key
+    #[serde(default)]
+    uploads: HashMap<String, UploadRecord>,
+    /// Last listing of S3 objects
+    #[serde(default)]
+    s3_objects: Option<S3Listing>,
+    /// Time of the last run per schedule, e.g. `full` or `cleanup`
+    #[serde(default)]
+    last_runs: HashMap<String, DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct S3Listing {
+    listed: DateTime<Utc>,
+    keys: HashSet<String>,
+}
+
+/// State store backed by a JSON file in the state directory.
+/// Without a directory the state is only kept in memory.
+#[derive(Debug)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+    /// Serializes the saves, which share the temporary file
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+impl StateStore {
+    pub fn in_memory() -> Self {
+        StateStore {
+            path: None,
+            state: Mutex::new(State::default()),
+            save_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Open the state stored in `dir`, creating the directory if needed
+    pub async fn open(dir: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(STATE_FILE);
+        let state = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(StateStore {
+            path: Some(path),
+            state: Mutex::new(state),
+            save_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Write the state to disk. The file is replaced atomically.
+    pub async fn save(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // The state is serialized once the lock is held so that the last save writes the
+        // latest state
+        let _save = self.save_lock.lock().await;
+        let content = serde_json::to_string_pretty(&*self.lock())?;
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Record an upload attempt, counting the attempts since the last success
+    pub fn record_upload(&self, mut record: UploadRecord) {
+        let mut state = self.lock();
+        record.attempts = state
+            .uploads
+            .get(&record.key)
+            .filter(|r| r.status != UploadStatus::Succeeded)
+            .map_or(1, |r| r.attempts + 1);
+        state.uploads.insert(record.key.clone(), record);
+    }
+
+    pub fn upload(&self, key: &str) -> Option<UploadRecord> {
+        self.lock().uploads.get(key).cloned()
+    }
+
+    /// All upload records, sorted from newest to oldest
+    pub fn uploads(&self) -> Vec<UploadRecord> {
+        let mut uploads: Vec<UploadRecord> = self.lock().uploads.values().cloned().collect();
+        uploads.sort_by_key(|r| std::cmp::Reverse(r.started));
+        uploads
+    }
+
+    /// Latest successful upload of a volume
+    pub fn last_success(&self, volume: &str) -> Option<UploadRecord> {
+        self.uploads()
+            .into_iter()
+            .find(|r| r.volume == volume && r.status == UploadStatus::Succeeded)
+    }
+
+    /// Forget upload records for which `keep` returns false
+    pub fn retain_uploads(&self, keep: impl Fn(&UploadRecord) -> bool) {
+        self.lock().uploads.retain(|_, r| keep(r));
+    }
+
+    /// S3 object keys from the last listing, if it is not older than `max_age`
+    pub fn s3_objects(&self, max_age: chrono::Duration) -> Option<HashSet<String>> {
+        self.lock()
+            .s3_objects
+            .as_ref()
+            .filter(|l| Utc::now() - l.listed <= max_age)
+            .map(|l| l.keys.clone())
+    }
+
+    /// Replace the known S3 object keys with a fresh listing
+    pub fn set_s3_objects(&self, keys: HashSet<String>) {
+        self.lock().s3_objects = Some(S3Listing {
+            listed: Utc::now(),
+            keys,
+        });
+    }
+
+    /// Update the known S3 object keys after an upload or a deletion
+    pub fn update_s3_object(&self, key: &str, exists: bool) {
+        if let Some(listing) = self.lock().s3_objects.as_mut() {
+            if exists {
+                listing.keys.insert(key.to_string());
+            } else {
+                listing.keys.remove(key);
+            }
+        }
+    }
+
+    /// Time of the last run of a schedule
+    pub fn last_run(&self, schedule: &str) -> Option<DateTime<Utc>> {
+        self.lock().last_runs.get(schedule).copied()
+    }
+
+    pub fn set_last_run(&self, schedule: &str, time: DateTime<Utc>) {
+        self.lock().last_runs.insert(schedule.to_string(), time);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // The state stays consistent even if a holder panicked, every update is a single insert
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test_state {
+    use super::*;
+
+    fn record(key: &str, status: UploadStatus) -> UploadRecord {
+        UploadRecord {
+            volume: "pool/vm-100-disk-0".to_string(),
+            snapshot: format!("pool/{key}"),
+            key: key.to_string(),
+            parent: None,
+            started: Utc::now(),
+            duration_ms: 10,
+            bytes: 1024,
+            attempts: 1,
+            status,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn consecutive_failures_are_counted() {
+        let store = StateStore::in_memory();
+        store.record_upload(record("a", UploadStatus::Failed));
+        store.record_upload(record("a", UploadStatus::Failed));
+        assert_eq!(store.upload("a").unwrap().attempts, 2);
+
+        store.record_upload(record("a", UploadStatus::Succeeded));
+        store.record_upload(record("a", UploadStatus::Failed));
+        assert_eq!(store.upload("a").unwrap().attempts, 1);
+
+        // An interrupted upload is resumed as a new attempt
+        store.record_upload(record("a", UploadStatus::Interrupted));
+        store.record_upload(record("a", UploadStatus::Succeeded));
+        assert_eq!(store.upload("a").unwrap().attempts, 3);
+    }
+
+    #[test]
+    fn s3_listing_expires() {
+        let store = StateStore::in_memory();
+        assert!(store.s3_objects(chrono::Duration::hours(1)).is_none());
+
+        store.set_s3_objects(HashSet::from(["a".to_string()]));
+        store.update_s3_object("b", true);
+        let keys = store.s3_objects(chrono::Duration::hours(1)).unwrap();
+        assert!(keys.contains("a") && keys.contains("b"));
+        assert!(store.s3_objects(chrono::Duration::seconds(-1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn persisted_to_disk() {
+        let dir = std::env::temp_dir().join(format!("zfs2s3-state-{}", std::process::id()));
+        let store = StateStore::open(&dir).await.unwrap();
+        store.record_upload(record("a", UploadStatus::Succeeded));
+        let now = Utc::now();
+        store.set_last_run("full", now);
+        store.save().await.unwrap();
+
+        let store = StateStore::open(&dir).await.unwrap();
+        assert!(store.last_success("pool/vm-100-disk-0").is_some());
+        assert_eq!(store.last_run("full"), Some(now));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_saves() {
+        let dir = std::env::temp_dir().join(format!("zfs2s3-saves-{}", std::process::id()));
+        let store = StateStore::open(&dir).await.unwrap();
+        store.record_upload(record("a", UploadStatus::Succeeded));
+        let saves = futures::future::join_all((0..16).map(|_| store.save())).await;
+        assert!(saves.iter().all(|r| r.is_ok()));
+
+        let store = StateStore::open(&dir).await.unwrap();
+        assert!(store.upload("a").is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
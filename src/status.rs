@@ -0,0 +1,176 @@
+/// Runtime status of the daemon, exposed by the HTTP server.
+use crate::progress::UploadProgress;
+use crate::state::{StateStore, UploadStatus};
+use crate::{SnapshotType, snapshot_type_of};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Operation {
+    pub name: String,
+    pub started: DateTime<Utc>,
+    /// Id of the run in the logs
+    pub run_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VolumeStatus {
+    pub last_full: Option<DateTime<Utc>>,
+    pub last_incremental: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Inner {
+    /// Scheduler tasks and whether they are running
+    tasks: BTreeMap<String, bool>,
+    /// Next scheduled run per schedule (full, incremental, cleanup)
+    next_runs: BTreeMap<String, DateTime<Utc>>,
+    operation: Option<Operation>,
+}
+
+/// Snapshot of the status returned by `/status`
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    pub tasks: BTreeMap<String, bool>,
+    pub next_runs: BTreeMap<String, DateTime<Utc>>,
+    pub operation: Option<Operation>,
+    pub uploads: Vec<UploadProgress>,
+    pub volumes: BTreeMap<String, VolumeStatus>,
+}
+
+pub struct Status {
+    state: Arc<StateStore>,
+    inner: Mutex<Inner>,
+}
+
+impl Status {
+    pub fn new(state: Arc<StateStore>) -> Self {
+        Status {
+            state,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Register a running task. The task is reported as stopped once the guard is dropped,
+    /// including when the task returns an error or panics.
+    pub fn task(self: &Arc<Self>, name: &str) -> TaskGuard {
+        self.lock().tasks.insert(name.to_string(), true);
+        TaskGuard {
+            status: Arc::clone(self),
+            name: name.to_string(),
+        }
+    }
+
+    /// True if all registered tasks are running
+    pub fn healthy(&self) -> bool {
+        self.lock().tasks.values().all(|alive| *alive)
+    }
+
+    pub fn set_next_run(&self, schedule: &str, next: DateTime<Utc>) {
+        self.lock().next_runs.insert(schedule.to_string(), next);
+    }
+
+    /// Mark an operation as in progress until the guard is dropped
+    pub fn start_operation(self: &Arc<Self>, name: &str) -> OperationGuard {
+        self.lock().operation = Some(Operation {
+            name: name.to_string(),
+            started: Utc::now(),
+            run_id: crate::logging::run_id(),
+        });
+        OperationGuard {
+            status: Arc::clone(self),
+        }
+    }
+
+    pub fn report(&self) -> StatusReport {
+        let mut volumes: BTreeMap<String, VolumeStatus> = BTreeMap::new();
+        // Records are sorted from newest to oldest, keep the first of each kind
+        for record in self.state.uploads() {
+            let volume = volumes.entry(record.volume.clone()).or_default();
+            match (record.status, snapshot_type_of(&record.snapshot)) {
+                (UploadStatus::Succeeded, SnapshotType::Full) => {
+                    volume.last_full.get_or_insert(record.started);
+                }
+                (UploadStatus::Succeeded, SnapshotType::Incremental) => {
+                    volume.last_incremental.get_or_insert(record.started);
+                }
+                (UploadStatus::Failed | UploadStatus::Interrupted, _) => {
+                    if volume.last_failure.is_none() {
+                        volume.last_failure = Some(record.started);
+                        volume.last_error = record.error;
+                    }
+                }
+            }
+        }
+
+        let inner = self.lock();
+        StatusReport {
+            tasks: inner.tasks.clone(),
+            next_runs: inner.next_runs.clone(),
+            operation: inner.operation.clone(),
+            uploads: crate::progress::uploads_in_progress(),
+            volumes,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct TaskGuard {
+    status: Arc<Status>,
+    name: String,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.status.lock().tasks.insert(self.name.clone(), false);
+    }
+}
+
+pub struct OperationGuard {
+    status: Arc<Status>,
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        self.status.lock().operation = None;
+    }
+}
+
+/// Channels used to run a backup or a cleanup immediately
+#[derive(Clone)]
+pub struct Triggers {
+    pub backup: mpsc::Sender<SnapshotType>,
+    pub cleanup: mpsc::Sender<()>,
+}
+
+#[cfg(test)]
+mod test_status {
+    use super::*;
+
+    #[test]
+    fn task_stopped_when_guard_dropped() {
+        let status = Arc::new(Status::new(Arc::new(StateStore::in_memory())));
+        let guard = status.task("backup");
+        assert!(status.healthy());
+        drop(guard);
+        assert!(!status.healthy());
+    }
+
+    #[test]
+    fn operation_cleared_when_guard_dropped() {
+        let status = Arc::new(Status::new(Arc::new(StateStore::in_memory())));
+        {
+            let _operation = status.start_operation("cleanup");
+            assert_eq!(status.report().operation.unwrap().name, "cleanup");
+        }
+        assert!(status.report().operation.is_none());
+    }
+}
@@ -0,0 +1,195 @@
+/// Bandwidth limiting of uploads with token buckets, following the limits configured per
+/// time window.
+use crate::config::{ConfigError, UploadPolicy};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use fast_glob::glob_match;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Interval at which the rate of a limiter is resolved again from its time windows
+const RATE_REFRESH: Duration = Duration::from_secs(1);
+
+/// Limit in bytes per second applying while the schedule matches, none when unlimited
+#[derive(Debug, Clone)]
+struct Window {
+    schedule: Option<Schedule>,
+    /// Time zone of the schedule
+    timezone: Tz,
+    limit: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    refreshed: Option<Instant>,
+    /// Available bytes, negative when readers are waiting for bytes already taken
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter. The bucket holds at most one second of traffic.
+#[derive(Debug)]
+pub struct RateLimiter {
+    windows: Vec<Window>,
+    default: Option<u64>,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    fn new(windows: Vec<Window>, default: Option<u64>) -> Self {
+        RateLimiter {
+            windows,
+            default,
+            bucket: Mutex::new(Bucket {
+                rate: None,
+                refreshed: None,
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Limit applying at `now`, from the first matching window
+    fn rate_at(&self, now: &DateTime<Utc>) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|w| {
+                w.schedule
+                    .as_ref()
+                    .is_none_or(|s| s.includes(now.with_timezone(&w.timezone)))
+            })
+            .map_or(self.default, |w| w.limit)
+    }
+
+    /// Wait until `bytes` can be sent
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take `bytes` from the bucket and return how long to wait before sending them
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+
+        if bucket
+            .refreshed
+            .is_none_or(|t| now.duration_since(t) >= RATE_REFRESH)
+        {
+            let rate = self.rate_at(&Utc::now());
+            if rate != bucket.rate {
+                bucket.rate = rate;
+                bucket.tokens = rate.unwrap_or_default() as f64;
+                bucket.updated = now;
+            }
+            bucket.refreshed = Some(now);
+        }
+
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+        bucket.updated = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+/// Bandwidth limits of the uploads of a sync
+pub struct Throttle {
+    /// Limiter shared by all uploads
+    global: Arc<RateLimiter>,
+    /// Windows applying to each upload of the matching volumes
+    volumes: Vec<(Vec<String>, Window)>,
+}
+
+impl Throttle {
+    pub fn new(config: &UploadPolicy, timezone: Tz) -> Result<Self, ConfigError> {
+        let mut global = Vec::new();
+        let mut volumes = Vec::new();
+        for rule in config.bandwidth.iter() {
+            let window = Window {
+                schedule: rule.schedule()?,
+                timezone,
+                limit: rule.limit()?,
+            };
+            if rule.volumes.is_empty() {
+                global.push(window);
+            } else {
+                volumes.push((rule.volumes.clone(), window));
+            }
+        }
+
+        Ok(Throttle {
+            global: Arc::new(RateLimiter::new(global, config.bandwidth_limit()?)),
+            volumes,
+        })
+    }
+
+    /// Limiters applying to an upload of `volume`
+    pub fn limiters(&self, volume: &str) -> Vec<Arc<RateLimiter>> {
+        let windows: Vec<Window> = self
+            .volumes
+            .iter()
+            .filter(|(patterns, _)| patterns.iter().any(|p| glob_match(p, volume)))
+            .map(|(_, window)| window.clone())
+            .collect();
+
+        let mut limiters = vec![Arc::clone(&self.global)];
+        if !windows.is_empty() {
+            limiters.push(Arc::new(RateLimiter::new(windows, None)));
+        }
+        limiters
+    }
+}
+
+#[cfg(test)]
+mod test_throttle {
+    use super::*;
+
+    #[test]
+    fn window_overrides_default() {
+        let limiter = RateLimiter::new(
+            vec![Window {
+                schedule: Some(Schedule::try_from("* * * * * * *").unwrap()),
+                timezone: Tz::UTC,
+                limit: Some(1000),
+            }],
+            None,
+        );
+        assert_eq!(limiter.rate_at(&Utc::now()), Some(1000));
+
+        let limiter = RateLimiter::new(
+            vec![Window {
+                schedule: Some(Schedule::try_from("* * * * * * 2000").unwrap()),
+                timezone: Tz::UTC,
+                limit: Some(1000),
+            }],
+            Some(5000),
+        );
+        assert_eq!(limiter.rate_at(&Utc::now()), Some(5000));
+    }
+
+    #[test]
+    fn bucket_delays_reads_above_rate() {
+        let limiter = RateLimiter::new(Vec::new(), Some(1000));
+        let now = Instant::now();
+        // One second of traffic is available immediately
+        assert_eq!(limiter.reserve(1000, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, now), Duration::from_millis(500));
+        // The debt is paid back over time
+        assert_eq!(
+            limiter.reserve(500, now + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+    }
+}
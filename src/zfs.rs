@@ -1,34 +1,98 @@
+use crate::config::{Config, DEFAULT_POLICY};
 /// A simple wrapper around ZFS commands to manage snapshots for backup purposes.
-use crate::BACKUP_SUFFIX_INCREMENTAL;
-use crate::config::Config;
+use crate::{BACKUP_SUFFIX, BACKUP_SUFFIX_INCREMENTAL};
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use fast_glob::glob_match;
-use std::collections::{HashMap, HashSet};
+use futures::{Stream, StreamExt};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
-use tokio::process::Command;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::process::{Child, ChildStdout, Command};
 
 pub const SUFFIX_SEPARATOR: &str = "@";
 
+/// User property of the datasets to opt in or out of backups: `on`, `off` or the name of a
+/// backup policy. It is inherited from the parent datasets.
+pub const BACKUP_PROPERTY: &str = "zfs2s3:backup";
+
+/// Value of the backup property of a dataset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackupProperty {
+    /// Never backed up, even if the volume matches a pattern
+    Off,
+    /// Backed up, even if the volume matches no pattern
+    On,
+    /// Backed up with the named policy
+    Policy(String),
+}
+
+impl BackupProperty {
+    /// Value of the property, none when it is not set. `on` and `off` are case-insensitive
+    /// and accept the usual boolean spellings, anything else names a policy.
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        match value.to_ascii_lowercase().as_str() {
+            "" | "-" => None,
+            "off" | "false" | "no" | "0" => Some(BackupProperty::Off),
+            "on" | "true" | "yes" | "1" => Some(BackupProperty::On),
+            _ => Some(BackupProperty::Policy(value.to_string())),
+        }
+    }
+}
+
+impl Display for BackupProperty {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupProperty::Off => write!(f, "off"),
+            BackupProperty::On => write!(f, "on"),
+            BackupProperty::Policy(policy) => write!(f, "{policy}"),
+        }
+    }
+}
+
 /// A mapping from volume names to their snapshots.
 /// Snapshots are sorted by creation time in descending order (latest first).
 #[derive(Debug)]
 pub struct VolumeSnapshotMap {
     pub volumes: HashMap<String, Vec<Snapshot>>,
+    /// Consistency groups, mapping a group name to its member volumes
+    pub groups: HashMap<String, Vec<String>>,
+    /// Backup policy of the volumes, the default policy when missing
+    pub policies: HashMap<String, String>,
+    /// Backup property of the volumes which have it set
+    pub properties: HashMap<String, BackupProperty>,
+    /// Volumes excluded by `backup.exclude` or the backup property, their objects on S3 and
+    /// their local snapshots are kept
+    pub excluded: HashSet<String>,
 }
 
 impl VolumeSnapshotMap {
     pub async fn new() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let snapshots = list_snapshots().await?;
-        let mut volumes: HashMap<String, Vec<Snapshot>> = list_volumes()
-            .await?
+        let listed = list_volume_properties().await?;
+        let mut volumes: HashMap<String, Vec<Snapshot>> = listed
             .iter()
-            .map(|v| (v.clone(), Vec::new()))
+            .map(|(v, _)| (v.clone(), Vec::new()))
+            .collect();
+        let properties = listed
+            .into_iter()
+            .filter_map(|(v, property)| property.map(|p| (v, p)))
             .collect();
 
         volumes.iter_mut().for_each(|(k, v)| {
             *v = Self::map_snapshot_to_volume(k.as_str(), &snapshots);
         });
-        Ok(VolumeSnapshotMap { volumes })
+        Ok(VolumeSnapshotMap {
+            volumes,
+            groups: HashMap::new(),
+            policies: HashMap::new(),
+            properties,
+            excluded: HashSet::new(),
+        })
     }
 
     pub fn volumes(&self) -> HashSet<String> {
@@ -36,19 +100,193 @@ impl VolumeSnapshotMap {
     }
 
     pub fn keep_volume_to_backup(self, config: &Config) -> Self {
-        let to_backup = self
-            .volumes
-            .iter()
-            .filter(|v| {
+        // A policy named by the property must exist, otherwise the property is ignored so that
+        // a misspelled `off` does not opt the volume in
+        let known_policy = |policy: &str| {
+            policy == DEFAULT_POLICY || config.backup.policies.iter().any(|p| p.name == policy)
+        };
+        let property = |volume: &str| match self.properties.get(volume) {
+            Some(BackupProperty::Policy(policy)) if !known_policy(policy) => {
+                log::warn!(
+                    "Volume {volume} has {BACKUP_PROPERTY}={policy} but there is no such policy, ignoring it"
+                );
+                None
+            }
+            property => property,
+        };
+
+        let mut excluded = HashSet::new();
+        let mut to_backup: HashMap<String, Vec<Snapshot>> = HashMap::new();
+        for (volume, snapshots) in self.volumes.iter() {
+            // Exclusions win over the property, which wins over the patterns
+            let backed_up = if config.backup.exclude.iter().any(|p| glob_match(p, volume)) {
+                excluded.insert(volume.clone());
+                false
+            } else {
+                match property(volume) {
+                    Some(BackupProperty::Off) => {
+                        excluded.insert(volume.clone());
+                        false
+                    }
+                    Some(_) => true,
+                    None => config
+                        .backup
+                        .volumes
+                        .iter()
+                        .chain(config.backup.groups.iter().flat_map(|g| g.volumes.iter()))
+                        .chain(config.backup.policies.iter().flat_map(|p| p.volumes.iter()))
+                        .any(|pattern| glob_match(pattern, volume)),
+                }
+            };
+            if backed_up {
+                to_backup.insert(volume.clone(), snapshots.clone());
+            }
+        }
+
+        // Assign volumes to consistency groups. A volume belongs to the first group matching it.
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+        let mut volumes: Vec<&String> = to_backup.keys().collect();
+        volumes.sort();
+        for volume in volumes {
+            let group = config
+                .backup
+                .groups
+                .iter()
+                .filter(|g| g.volumes.iter().any(|pattern| glob_match(pattern, volume)))
+                .collect::<Vec<_>>();
+            if let Some(first) = group.first() {
+                if group.len() > 1 {
+                    log::warn!(
+                        "Volume {volume} matches several consistency groups, using {}",
+                        first.name
+                    );
+                }
+                groups
+                    .entry(first.name.clone())
+                    .or_default()
+                    .push(volume.clone());
+            }
+        }
+
+        // `zfs snapshot` is only atomic within one pool, a group spanning several pools cannot
+        // be snapshotted consistently and its members are snapshotted on their own
+        groups.retain(|group, members| {
+            let pools: BTreeSet<&str> = members.iter().map(|m| pool_of(m)).collect();
+            if pools.len() > 1 {
+                log::error!(
+                    "Consistency group {group} spans several pools ({}), its volumes are snapshotted separately",
+                    pools.iter().copied().collect::<Vec<_>>().join(", ")
+                );
+            }
+            pools.len() <= 1
+        });
+
+        // Resolve the policy of the volumes: the one named by the property, otherwise the first
+        // policy matching the volume
+        let mut policies: HashMap<String, String> = to_backup
+            .keys()
+            .filter_map(|volume| {
+                if let Some(BackupProperty::Policy(policy)) = self.properties.get(volume)
+                    && known_policy(policy)
+                {
+                    if policy == DEFAULT_POLICY {
+                        return None;
+                    }
+                    return Some((volume.clone(), policy.clone()));
+                }
                 config
                     .backup
-                    .volumes
+                    .policies
                     .iter()
-                    .any(|pattern| glob_match(pattern, v.0))
+                    .find(|p| p.volumes.iter().any(|pattern| glob_match(pattern, volume)))
+                    .map(|p| (volume.clone(), p.name.clone()))
             })
+            .collect();
+
+        // Members of a consistency group are snapshotted together, they use the policy of the
+        // first member
+        for (group, members) in groups.iter() {
+            let policy = members
+                .first()
+                .and_then(|m| policies.get(m))
+                .cloned()
+                .unwrap_or(DEFAULT_POLICY.to_string());
+            for member in members {
+                let current = policies.get(member).map_or(DEFAULT_POLICY, |p| p.as_str());
+                if current != policy {
+                    log::warn!(
+                        "Volume {member} of consistency group {group} uses policy {policy} of the group instead of {current}"
+                    );
+                    policies.insert(member.clone(), policy.clone());
+                }
+            }
+        }
+
+        VolumeSnapshotMap {
+            volumes: to_backup,
+            groups,
+            policies,
+            properties: self.properties,
+            excluded,
+        }
+    }
+
+    /// Name of the backup policy of the volume
+    pub fn policy_of(&self, volume: &str) -> &str {
+        self.policies
+            .get(volume)
+            .map_or(DEFAULT_POLICY, |p| p.as_str())
+    }
+
+    /// Volumes of the backup policy named `policy`, with their snapshots
+    pub fn with_policy(&self, policy: &str) -> Self {
+        let volumes: HashMap<String, Vec<Snapshot>> = self
+            .volumes
+            .iter()
+            .filter(|(volume, _)| self.policy_of(volume) == policy)
             .map(|(k, v)| (k.clone(), v.clone()))
             .collect();
-        VolumeSnapshotMap { volumes: to_backup }
+        VolumeSnapshotMap {
+            groups: self
+                .groups
+                .iter()
+                .filter(|(_, members)| members.iter().all(|m| volumes.contains_key(m)))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            policies: self
+                .policies
+                .iter()
+                .filter(|(volume, _)| volumes.contains_key(*volume))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            properties: self
+                .properties
+                .iter()
+                .filter(|(volume, _)| volumes.contains_key(*volume))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            excluded: self.excluded.clone(),
+            volumes,
+        }
+    }
+
+    /// Name of the consistency group the volume belongs to, if any
+    pub fn group_of(&self, volume: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|(_, members)| members.iter().any(|m| m == volume))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Split the volumes into units that must be snapshotted together.
+    /// Each consistency group is one unit, every other volume is a unit on its own.
+    pub fn snapshot_units(&self) -> Vec<Vec<String>> {
+        let mut units: Vec<Vec<String>> = self.groups.values().cloned().collect();
+        self.volumes
+            .keys()
+            .filter(|v| self.group_of(v).is_none())
+            .for_each(|v| units.push(vec![v.clone()]));
+        units
     }
 
     pub async fn refresh(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -78,11 +316,17 @@ impl VolumeSnapshotMap {
         snaps
     }
 
+    /// Apply the retention policy and delete local snapshots accordingly.
+    /// Returns the names of the deleted snapshots.
     pub async fn apply_retention_policy(
         &mut self,
         config: &Config,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        for (_, snapshots) in self.volumes.iter_mut() {
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let before = self.volumes.clone();
+
+        for (volume, snapshots) in self.volumes.iter_mut() {
+            let policy = config.policy(self.policies.get(volume).map_or(DEFAULT_POLICY, |p| p));
+
             // Save all snapshots that should be excluded from cleanup
             let excluded_snapshots: Vec<Snapshot> = snapshots
                 .iter()
@@ -101,10 +345,10 @@ impl VolumeSnapshotMap {
                 .iter()
                 .enumerate()
                 .filter(|(_, s)| !s.name.contains(BACKUP_SUFFIX_INCREMENTAL))
-                .nth(config.cleanup.keep_min)
+                .nth(policy.keep_min)
             {
                 // There is at least `keep_min` full snapshots, filter with retention policy
-                let timestamp_cutoff = config.cleanup.keep_duration()?;
+                let timestamp_cutoff = policy.keep_duration()?;
 
                 // Find the first snapshot older than the cutoff timestamp which can be deleted
                 // since we only filter snapshots after the minimum kept full snapshots.
@@ -132,8 +376,39 @@ impl VolumeSnapshotMap {
             });
         }
 
-        sync_snapshots(self).await?;
-        Ok(())
+        self.align_groups_retention(&before);
+
+        sync_snapshots(self, config.backup.delete_excluded).await
+    }
+
+    /// Make sure members of a consistency group keep the same snapshots.
+    /// A snapshot kept for one member is kept for every member of the group.
+    fn align_groups_retention(&mut self, before: &HashMap<String, Vec<Snapshot>>) {
+        for members in self.groups.values() {
+            let kept_suffixes: HashSet<String> = members
+                .iter()
+                .filter_map(|m| self.volumes.get(m))
+                .flat_map(|snapshots| snapshots.iter().filter_map(|s| s.suffix()))
+                .map(|s| s.to_string())
+                .collect();
+
+            for member in members {
+                let (Some(original), Some(kept)) = (before.get(member), self.volumes.get(member))
+                else {
+                    continue;
+                };
+                let kept_names: HashSet<&str> = kept.iter().map(|s| s.name.as_str()).collect();
+                let aligned = original
+                    .iter()
+                    .filter(|s| {
+                        kept_names.contains(s.name.as_str())
+                            || s.suffix().is_some_and(|x| kept_suffixes.contains(x))
+                    })
+                    .cloned()
+                    .collect();
+                self.volumes.insert(member.clone(), aligned);
+            }
+        }
     }
 }
 
@@ -180,6 +455,13 @@ impl Snapshot {
         Ok(Snapshot { name, creation })
     }
 
+    /// Snapshot name without the volume, i.e. the part after `@`
+    pub fn suffix(&self) -> Option<&str> {
+        self.name
+            .split_once(SUFFIX_SEPARATOR)
+            .map(|(_, suffix)| suffix)
+    }
+
     pub fn to_key(&self) -> Result<&str, Box<dyn std::error::Error + Send + Sync>> {
         match self.name.split('/').next_back() {
             Some(key) => Ok(key),
@@ -192,6 +474,8 @@ impl Snapshot {
 pub enum ZfsError {
     CommandError(String),
     ChildError,
+    /// `zfs send` exited with an error before the end of the stream
+    SendFailed(ExitStatus),
 }
 
 impl Display for ZfsError {
@@ -199,18 +483,21 @@ impl Display for ZfsError {
         match self {
             ZfsError::CommandError(s) => write!(f, "ZFS command error: {}", s),
             ZfsError::ChildError => write!(f, "Failed to capture stdout from ZFS child process"),
+            ZfsError::SendFailed(status) => write!(f, "zfs send failed: {}", status),
         }
     }
 }
 
 impl std::error::Error for ZfsError {}
 
-async fn list_volumes() -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+/// Names of the ZFS volumes with their backup property, none when it is not set
+pub async fn list_volume_properties()
+-> Result<Vec<(String, Option<BackupProperty>)>, Box<dyn std::error::Error + Send + Sync>> {
     let output = Command::new("zfs")
         .arg("list")
         .arg("-H")
         .arg("-o")
-        .arg("name")
+        .arg(format!("name,{BACKUP_PROPERTY}"))
         .arg("-t")
         .arg("volume")
         .output()
@@ -218,14 +505,29 @@ async fn list_volumes() -> Result<Vec<String>, Box<dyn std::error::Error + Send
 
     if output.status.success() {
         let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        let volumes: Vec<String> = stdout.lines().map(|line| line.to_string()).collect();
-        Ok(volumes)
+        Ok(parse_volume_properties(&stdout))
     } else {
         let stderr = String::from_utf8_lossy(&output.stderr).to_string();
         Err(ZfsError::CommandError(stderr).into())
     }
 }
 
+/// Name of the pool of a volume
+fn pool_of(volume: &str) -> &str {
+    volume.split_once('/').map_or(volume, |(pool, _)| pool)
+}
+
+/// Parse the output of `zfs list -H -o name,<property>`
+fn parse_volume_properties(output: &str) -> Vec<(String, Option<BackupProperty>)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once('\t').unwrap_or((line, "-"));
+            (!name.is_empty()).then(|| (name.to_string(), BackupProperty::parse(value)))
+        })
+        .collect()
+}
+
 async fn list_snapshots() -> Result<Vec<Snapshot>, Box<dyn std::error::Error + Send + Sync>> {
     let output = Command::new("zfs")
         .arg("list")
@@ -268,36 +570,194 @@ pub async fn snapshot(name: &str) -> Result<(), Box<dyn std::error::Error + Send
     }
 }
 
+/// Take snapshots of several ZFS datasets atomically with a single command
+/// - `names`: The names of the snapshots in the format "pool/dataset@snapshot"
+pub async fn snapshot_many(
+    names: &[String],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let status = Command::new("zfs")
+        .arg("snapshot")
+        .args(names)
+        .status()
+        .await?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(ZfsError::CommandError(format!("Failed to take snapshots {}", names.join(" "))).into())
+    }
+}
+
+/// Receive a send stream into a dataset and return the number of bytes received
+/// - `force`: roll back the dataset to its most recent snapshot first (`zfs receive -F`)
+pub async fn receive<S, E>(
+    dataset: &str,
+    force: bool,
+    mut stream: S,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut command = Command::new("zfs");
+    command.arg("receive");
+    if force {
+        command.arg("-F");
+    }
+    let mut child = command
+        .arg(dataset)
+        .stdin(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().ok_or(ZfsError::ChildError)?;
+
+    let mut bytes = 0;
+    let mut write_error = None;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(Into::into)?;
+        // Writing fails if the command exited early, its status explains why
+        if let Err(e) = stdin.write_all(&chunk).await {
+            write_error = Some(e);
+            break;
+        }
+        bytes += chunk.len() as u64;
+    }
+    drop(stdin);
+
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(
+            ZfsError::CommandError(format!("Failed to receive snapshot into {dataset}")).into(),
+        );
+    }
+    match write_error {
+        Some(e) => Err(e.into()),
+        None => Ok(bytes),
+    }
+}
+
+type ExitFuture = Pin<Box<dyn Future<Output = std::io::Result<ExitStatus>> + Send>>;
+
+/// Output of a `zfs send` command. The command is killed when the stream is dropped,
+/// e.g. when an upload is aborted. Reading fails at the end of the stream if the command
+/// did not succeed, so that an incomplete stream is never uploaded.
+pub struct SendStream {
+    child: Option<Child>,
+    stdout: ChildStdout,
+    /// Exit of the command, once the end of the stream is reached
+    exit: Option<ExitFuture>,
+}
+
+impl SendStream {
+    fn spawn(command: &mut Command) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut child = command
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or(ZfsError::ChildError)?;
+        Ok(SendStream {
+            child: Some(child),
+            stdout,
+            exit: None,
+        })
+    }
+}
+
+impl AsyncRead for SendStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.stdout).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => {}
+            other => return other,
+        }
+
+        // End of the stream, check the exit status of the command
+        if self.exit.is_none() {
+            let Some(mut child) = self.child.take() else {
+                return Poll::Ready(Ok(()));
+            };
+            self.exit = Some(Box::pin(async move { child.wait().await }));
+        }
+        let Some(exit) = self.exit.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let status = std::task::ready!(exit.as_mut().poll(cx))?;
+        self.exit = None;
+        if status.success() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Ready(Err(std::io::Error::other(ZfsError::SendFailed(status))))
+        }
+    }
+}
+
 /// Send a snapshot of a ZFS dataset to a stream
 /// - `name`: The name of the snapshot in the format "pool/dataset@snapshot"
+/// - `flags`: Additional flags of `zfs send`, e.g. "--raw"
 pub async fn stream_snapshot(
     name: &str,
-) -> Result<tokio::process::ChildStdout, Box<dyn std::error::Error + Send + Sync>> {
-    let mut child = Command::new("zfs")
-        .arg("send")
-        .arg(name)
-        .stdout(std::process::Stdio::piped())
-        .spawn()?;
-
-    child.stdout.take().ok_or(ZfsError::ChildError.into())
+    flags: &[String],
+) -> Result<SendStream, Box<dyn std::error::Error + Send + Sync>> {
+    SendStream::spawn(Command::new("zfs").arg("send").args(flags).arg(name))
 }
 
 /// Send an incremental snapshot of a ZFS dataset to a stream
 /// - `from`: The name of the base snapshot in the format "pool/dataset@snapshot"
 /// - `to`: The name of the target snapshot in the format "pool/dataset@snapshot"
+/// - `flags`: Additional flags of `zfs send`, e.g. "--raw"
 pub async fn stream_incremental_snapshot(
     from: &str,
     to: &str,
-) -> Result<tokio::process::ChildStdout, Box<dyn std::error::Error + Send + Sync>> {
-    let mut child = Command::new("zfs")
-        .arg("send")
-        .arg("-i")
-        .arg(from)
-        .arg(to)
-        .stdout(std::process::Stdio::piped())
-        .spawn()?;
+    flags: &[String],
+) -> Result<SendStream, Box<dyn std::error::Error + Send + Sync>> {
+    SendStream::spawn(
+        Command::new("zfs")
+            .arg("send")
+            .args(flags)
+            .arg("-i")
+            .arg(from)
+            .arg(to),
+    )
+}
+
+/// Estimate the size of a send stream with `zfs send -nvP`
+/// - `from`: The base snapshot of an incremental stream, none for a full stream
+/// - `to`: The name of the snapshot in the format "pool/dataset@snapshot"
+/// - `flags`: Additional flags of `zfs send`, which change the size of the stream
+pub async fn estimate_send_size(
+    from: Option<&str>,
+    to: &str,
+    flags: &[String],
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut command = Command::new("zfs");
+    command.arg("send").arg("-nvP").args(flags);
+    if let Some(from) = from {
+        command.arg("-i").arg(from);
+    }
+    let output = command.arg(to).output().await?;
+
+    if !output.status.success() {
+        return Err(ZfsError::CommandError(format!(
+            "Failed to estimate the size of {}: {}",
+            to,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+    parse_send_size(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| ZfsError::CommandError(format!("No size in the estimate of {}", to)).into())
+}
 
-    child.stdout.take().ok_or(ZfsError::ChildError.into())
+/// Parse the `size` line of the output of `zfs send -nvP`
+fn parse_send_size(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("size"))
+        .find_map(|size| size.trim().parse().ok())
 }
 
 /// Delete a snapshot of a ZFS dataset
@@ -317,24 +777,49 @@ pub async fn delete_snapshot(name: &str) -> Result<(), Box<dyn std::error::Error
 }
 
 /// Sync snapshots on ZFS.
-/// Remove snapshots that are not present in the provided VolumeSnapshotMap.
+/// Remove the snapshots of the backed up volumes that are not present in the provided
+/// VolumeSnapshotMap. Returns the names of the deleted snapshots.
 async fn sync_snapshots(
     volumes: &VolumeSnapshotMap,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    delete_excluded: bool,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
     let snapshots = list_snapshots().await?;
+    let mut deleted = Vec::new();
 
-    for snapshot in snapshots.iter() {
-        let exists_locally = volumes
-            .volumes
-            .values()
-            .flat_map(|v| v.iter())
-            .any(|s| s.name == snapshot.name);
-        if !exists_locally {
-            delete_snapshot(&snapshot.name).await?;
-        }
+    for snapshot in snapshots_to_delete(volumes, &snapshots, delete_excluded) {
+        delete_snapshot(&snapshot.name).await?;
+        log::info!(snapshot = snapshot.name; "Deleted snapshot {}", snapshot.name);
+        crate::metrics::retention_deleted("zfs");
+        deleted.push(snapshot.name.clone());
     }
 
-    Ok(())
+    Ok(deleted)
+}
+
+/// Snapshots removed from the volumes of the map. The snapshots of the other datasets are kept,
+/// except the ones taken by zfs2s3 of the excluded volumes when `delete_excluded` is set.
+fn snapshots_to_delete<'a>(
+    volumes: &VolumeSnapshotMap,
+    snapshots: &'a [Snapshot],
+    delete_excluded: bool,
+) -> Vec<&'a Snapshot> {
+    snapshots
+        .iter()
+        .filter(|snapshot| {
+            let volume = snapshot
+                .name
+                .split_once(SUFFIX_SEPARATOR)
+                .map_or(snapshot.name.as_str(), |(volume, _)| volume);
+            match volumes.volumes.get(volume) {
+                Some(kept) => !kept.iter().any(|s| s.name == snapshot.name),
+                None => {
+                    delete_excluded
+                        && volumes.excluded.contains(volume)
+                        && snapshot.name.contains(BACKUP_SUFFIX)
+                }
+            }
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -367,6 +852,288 @@ mod test_snapshot {
         assert!(result.is_err());
     }
 
+    fn snapshot(name: &str, creation: i64) -> Snapshot {
+        Snapshot {
+            name: name.to_string(),
+            creation: DateTime::<Utc>::from_timestamp_secs(creation).unwrap(),
+        }
+    }
+
+    #[test]
+    fn snapshot_units_with_group() {
+        let map = VolumeSnapshotMap {
+            excluded: HashSet::new(),
+            volumes: HashMap::from([
+                ("pool/vm-100-disk-0".to_string(), Vec::new()),
+                ("pool/vm-100-disk-1".to_string(), Vec::new()),
+                ("pool/vm-101-disk-0".to_string(), Vec::new()),
+            ]),
+            groups: HashMap::from([(
+                "db".to_string(),
+                vec![
+                    "pool/vm-100-disk-0".to_string(),
+                    "pool/vm-100-disk-1".to_string(),
+                ],
+            )]),
+            policies: HashMap::new(),
+            properties: HashMap::new(),
+        };
+
+        let mut units = map.snapshot_units();
+        units.sort();
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].len(), 2);
+        assert_eq!(units[1], vec!["pool/vm-101-disk-0".to_string()]);
+        assert_eq!(map.group_of("pool/vm-100-disk-1"), Some("db"));
+        assert_eq!(map.group_of("pool/vm-101-disk-0"), None);
+    }
+
+    #[test]
+    fn volumes_resolved_to_policies() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 0 * * Sun *"
+incremental = "0 0 4 * * * *"
+volumes = ["pool/vm-2*"]
+
+[[backup.group]]
+name = "db"
+volumes = ["pool/vm-100-*"]
+
+[[backup.policy]]
+name = "databases"
+volumes = ["pool/vm-100-disk-0", "pool/vm-101-*"]
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "90d"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+        let config = Config::try_from(CONFIG).unwrap();
+        let map = VolumeSnapshotMap {
+            excluded: HashSet::new(),
+            volumes: [
+                "pool/vm-100-disk-0",
+                "pool/vm-100-disk-1",
+                "pool/vm-101-disk-0",
+                "pool/vm-200-disk-0",
+                "pool/vm-300-disk-0",
+            ]
+            .iter()
+            .map(|v| (v.to_string(), Vec::new()))
+            .collect(),
+            groups: HashMap::new(),
+            policies: HashMap::new(),
+            properties: HashMap::new(),
+        }
+        .keep_volume_to_backup(&config);
+
+        assert_eq!(map.volumes.len(), 4);
+        assert_eq!(map.policy_of("pool/vm-101-disk-0"), "databases");
+        assert_eq!(map.policy_of("pool/vm-200-disk-0"), DEFAULT_POLICY);
+        // Members of a group follow the policy of the first member
+        assert_eq!(map.policy_of("pool/vm-100-disk-1"), "databases");
+
+        let databases = map.with_policy("databases");
+        assert_eq!(databases.volumes.len(), 3);
+        assert_eq!(databases.groups["db"].len(), 2);
+        assert_eq!(map.with_policy(DEFAULT_POLICY).volumes.len(), 1);
+    }
+
+    #[test]
+    fn group_spanning_pools_is_split() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 0 * * Sun *"
+incremental = "0 0 4 * * * *"
+
+[[backup.group]]
+name = "db"
+volumes = ["*/db-*"]
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "90d"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+        let config = Config::try_from(CONFIG).unwrap();
+        let map = VolumeSnapshotMap {
+            excluded: HashSet::new(),
+            volumes: ["fast/db-data", "slow/db-logs"]
+                .iter()
+                .map(|v| (v.to_string(), Vec::new()))
+                .collect(),
+            groups: HashMap::new(),
+            policies: HashMap::new(),
+            properties: HashMap::new(),
+        }
+        .keep_volume_to_backup(&config);
+
+        assert_eq!(map.volumes.len(), 2);
+        assert!(map.groups.is_empty());
+        assert_eq!(map.snapshot_units().len(), 2);
+    }
+
+    #[test]
+    fn volumes_selected_with_exclusions_and_property() {
+        const CONFIG: &str = r#"
+[backup]
+schedule = "0 0 0 * * Sun *"
+incremental = "0 0 4 * * * *"
+volumes = ["pool/vm-2*"]
+exclude = ["pool/vm-*-scratch"]
+
+[[backup.policy]]
+name = "databases"
+volumes = ["pool/vm-100-*"]
+
+[cleanup]
+schedule = "0 0 5 * * * *"
+keep_min = 3
+keep_duration = "90d"
+
+[s3]
+bucket = "my-bucket"
+url = "http://localhost:3900"
+region = "garage"
+"#;
+        let config = Config::try_from(CONFIG).unwrap();
+        let listed = parse_volume_properties(
+            "pool/vm-200-disk-0\t-\n\
+             pool/vm-201-disk-0\toff\n\
+             pool/vm-202-scratch\ton\n\
+             pool/vm-300-disk-0\ton\n\
+             pool/vm-400-disk-0\tdatabases\n\
+             pool/vm-500-disk-0\tmissing\n\
+             pool/vm-600-disk-0\tOFF\n",
+        );
+        assert_eq!(listed[0], ("pool/vm-200-disk-0".to_string(), None));
+        assert_eq!(listed[1].1, Some(BackupProperty::Off));
+        assert_eq!(listed[6].1, Some(BackupProperty::Off));
+        assert_eq!(BackupProperty::parse("No"), Some(BackupProperty::Off));
+
+        let map = VolumeSnapshotMap {
+            excluded: HashSet::new(),
+            volumes: listed
+                .iter()
+                .map(|(v, _)| (v.clone(), Vec::new()))
+                .collect(),
+            groups: HashMap::new(),
+            policies: HashMap::new(),
+            properties: listed
+                .into_iter()
+                .filter_map(|(v, p)| p.map(|p| (v, p)))
+                .collect(),
+        }
+        .keep_volume_to_backup(&config);
+
+        let mut volumes: Vec<&str> = map.volumes.keys().map(String::as_str).collect();
+        volumes.sort();
+        assert_eq!(
+            volumes,
+            [
+                "pool/vm-200-disk-0",
+                "pool/vm-300-disk-0",
+                "pool/vm-400-disk-0"
+            ]
+        );
+        assert_eq!(map.policy_of("pool/vm-400-disk-0"), "databases");
+        // An unknown policy is ignored, the volume matches no pattern
+        assert!(!map.volumes.contains_key("pool/vm-500-disk-0"));
+        let mut excluded: Vec<&str> = map.excluded.iter().map(String::as_str).collect();
+        excluded.sort();
+        assert_eq!(
+            excluded,
+            [
+                "pool/vm-201-disk-0",
+                "pool/vm-202-scratch",
+                "pool/vm-600-disk-0"
+            ]
+        );
+    }
+
+    #[test]
+    fn align_groups_retention_keeps_same_suffixes() {
+        let a = "pool/vm-100-disk-0";
+        let b = "pool/vm-100-disk-1";
+        let before = HashMap::from([
+            (
+                a.to_string(),
+                vec![
+                    snapshot(&format!("{a}@s2"), 2),
+                    snapshot(&format!("{a}@s1"), 1),
+                ],
+            ),
+            (
+                b.to_string(),
+                vec![
+                    snapshot(&format!("{b}@s2"), 2),
+                    snapshot(&format!("{b}@s1"), 1),
+                ],
+            ),
+        ]);
+        let mut map = VolumeSnapshotMap {
+            excluded: HashSet::new(),
+            volumes: HashMap::from([
+                (a.to_string(), before[a].clone()),
+                (b.to_string(), vec![snapshot(&format!("{b}@s2"), 2)]),
+            ]),
+            groups: HashMap::from([("db".to_string(), vec![a.to_string(), b.to_string()])]),
+            policies: HashMap::new(),
+            properties: HashMap::new(),
+        };
+
+        map.align_groups_retention(&before);
+        assert_eq!(map.volumes[b].len(), 2);
+        assert_eq!(map.volumes[b][1].name, format!("{b}@s1"));
+    }
+
+    #[test]
+    fn only_snapshots_of_mapped_volumes_deleted() {
+        let kept = snapshot("pool/vm-100-disk-0@auto-backup-2024-05-02T05:00:00Z", 2);
+        let removed = snapshot("pool/vm-100-disk-0@auto-backup-2024-05-01T05:00:00Z", 1);
+        let excluded = snapshot("pool/vm-300-disk-0@auto-backup-2024-05-01T05:00:00Z", 1);
+        let manual = snapshot("pool/vm-300-disk-0@before-upgrade", 1);
+        let other = snapshot("pool/home@daily", 1);
+        let snapshots = [kept.clone(), removed, excluded, manual, other];
+        let map = VolumeSnapshotMap {
+            excluded: HashSet::from(["pool/vm-300-disk-0".to_string()]),
+            volumes: HashMap::from([("pool/vm-100-disk-0".to_string(), vec![kept])]),
+            groups: HashMap::new(),
+            policies: HashMap::new(),
+            properties: HashMap::new(),
+        };
+
+        let names = |delete_excluded| {
+            snapshots_to_delete(&map, &snapshots, delete_excluded)
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(false),
+            ["pool/vm-100-disk-0@auto-backup-2024-05-01T05:00:00Z"]
+        );
+        // Only the snapshots taken by zfs2s3 of the excluded volumes are deleted
+        assert_eq!(
+            names(true),
+            [
+                "pool/vm-100-disk-0@auto-backup-2024-05-01T05:00:00Z",
+                "pool/vm-300-disk-0@auto-backup-2024-05-01T05:00:00Z"
+            ]
+        );
+    }
+
     #[test]
     fn exclude_glob_pattern() {
         let snapshot = Snapshot {
@@ -376,4 +1143,28 @@ mod test_snapshot {
         let pattern = "**/*@__base__*";
         assert!(glob_match(pattern, &snapshot.name));
     }
+
+    #[test]
+    fn parse_estimated_send_size() {
+        let output = "incremental\tauto-backup-2025-10-17T04:06:55Z\tpool/vm-100-disk-0@auto-backup-incremental-2025-10-18T04:06:55Z\t123456\nsize\t123456\n";
+        assert_eq!(parse_send_size(output), Some(123456));
+        assert_eq!(parse_send_size(""), None);
+    }
+
+    #[tokio::test]
+    async fn send_stream_fails_when_command_fails() {
+        use tokio::io::AsyncReadExt;
+
+        let mut stream =
+            SendStream::spawn(Command::new("sh").arg("-c").arg("echo partial; exit 1")).unwrap();
+        let mut output = Vec::new();
+        let error = stream.read_to_end(&mut output).await.unwrap_err();
+        assert_eq!(output, b"partial\n");
+        assert!(error.to_string().contains("zfs send failed"));
+
+        let mut stream = SendStream::spawn(Command::new("sh").arg("-c").arg("echo ok")).unwrap();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, b"ok\n");
+    }
 }